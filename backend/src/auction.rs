@@ -0,0 +1,185 @@
+//! selection of advertisements by bid when more match than fit in a response
+use crate::database::BidType;
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+use std::cmp::Reverse;
+use std::env;
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// winners pay their own bid
+    FirstPrice,
+    /// winners pay the bid of the next highest bidder, or the floor
+    #[default]
+    SecondPrice,
+}
+
+impl Rule {
+    /// read from `AUCTION_RULE`, `second_price` when unset
+    pub fn from_env() -> Self {
+        let Ok(raw) = env::var("AUCTION_RULE") else {
+            return Self::default();
+        };
+        let rule: Result<Rule, serde::de::value::Error> =
+            Rule::deserialize(raw.as_str().into_deserializer());
+        rule.unwrap_or_else(|err| {
+            tracing::warn!("ignore AUCTION_RULE {}: {}", raw, err);
+            Self::default()
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Won<T> {
+    pub item: T,
+    /// clearing price per thousand impressions, in micros
+    pub price: i64,
+}
+
+/// rank `bids` of `(item, ecpm)` and clear the top `slots`
+///
+/// Bids under `floor` are dropped. Ties keep the order of `bids`, so callers
+/// pass them in a deterministic order.
+pub fn clear<T>(mut bids: Vec<(T, i64)>, slots: usize, floor: i64, rule: Rule) -> Vec<Won<T>> {
+    bids.retain(|(_, ecpm)| *ecpm >= floor);
+    bids.sort_by_key(|(_, ecpm)| Reverse(*ecpm));
+
+    let next: Vec<i64> = bids
+        .iter()
+        .skip(1)
+        .map(|(_, ecpm)| *ecpm)
+        .chain([floor])
+        .collect();
+    bids.into_iter()
+        .zip(next)
+        .take(slots)
+        .map(|((item, ecpm), next)| Won {
+            item,
+            price: match rule {
+                Rule::FirstPrice => ecpm,
+                Rule::SecondPrice => next.max(floor),
+            },
+        })
+        .collect()
+}
+
+/// expected price per thousand impressions of a bid, in micros
+///
+/// `ctr` is the estimated click-through rate, used to compare CPC bids with CPM bids.
+pub fn ecpm(bid: i64, bid_type: BidType, ctr: f64) -> i64 {
+    match bid_type {
+        BidType::Cpm => bid,
+        BidType::Cpc => (bid as f64 * ctr * 1000.0) as i64,
+    }
+}
+
+/// price per click of a CPC bid cleared at `price` per thousand impressions, in micros
+///
+/// Inverts [`ecpm`] at the same `ctr`, never above the bid.
+pub fn cpc(price: i64, bid: i64, ctr: f64) -> i64 {
+    if ctr <= 0.0 {
+        return bid;
+    }
+    ((price as f64 / (ctr * 1000.0)) as i64).min(bid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bids() -> Vec<(&'static str, i64)> {
+        vec![("a", 3000), ("b", 5000), ("c", 1000), ("d", 4000)]
+    }
+
+    #[test]
+    fn first_price_pays_own_bid() {
+        let won = clear(bids(), 2, 0, Rule::FirstPrice);
+        assert_eq!(
+            won,
+            vec![
+                Won {
+                    item: "b",
+                    price: 5000
+                },
+                Won {
+                    item: "d",
+                    price: 4000
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn second_price_pays_next_bid() {
+        let won = clear(bids(), 2, 0, Rule::SecondPrice);
+        // the runner-up of the last slot is beyond `slots`, and still sets its price
+        assert_eq!(
+            won,
+            vec![
+                Won {
+                    item: "b",
+                    price: 4000
+                },
+                Won {
+                    item: "d",
+                    price: 3000
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn last_bidder_pays_floor() {
+        let won = clear(bids(), 4, 500, Rule::SecondPrice);
+        assert_eq!(won.len(), 4);
+        assert_eq!(
+            won[3],
+            Won {
+                item: "c",
+                price: 500
+            }
+        );
+    }
+
+    #[test]
+    fn floor_excludes_lower_bids() {
+        let won = clear(bids(), 4, 3000, Rule::SecondPrice);
+        let items: Vec<_> = won.iter().map(|x| x.item).collect();
+        assert_eq!(items, vec!["b", "d", "a"]);
+        // the bid under the floor does not set the price of the last winner
+        assert_eq!(won[2].price, 3000);
+        assert!(clear(bids(), 4, 6000, Rule::FirstPrice).is_empty());
+    }
+
+    #[test]
+    fn ties_keep_input_order() {
+        let bids = vec![("a", 2000), ("b", 2000), ("c", 2000)];
+        let won = clear(bids, 2, 0, Rule::SecondPrice);
+        assert_eq!(
+            won,
+            vec![
+                Won {
+                    item: "a",
+                    price: 2000
+                },
+                Won {
+                    item: "b",
+                    price: 2000
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn cpc_converts_to_ecpm_and_back() {
+        assert_eq!(ecpm(2000, BidType::Cpm, 0.01), 2000);
+        // 0.5 per click at 1% click-through is 5 per thousand impressions
+        assert_eq!(ecpm(500_000, BidType::Cpc, 0.01), 5_000_000);
+        assert_eq!(cpc(5_000_000, 500_000, 0.01), 500_000);
+        // a second price under the bid is charged per click in proportion
+        assert_eq!(cpc(4_000_000, 500_000, 0.01), 400_000);
+        assert_eq!(cpc(6_000_000, 500_000, 0.01), 500_000);
+        assert_eq!(cpc(0, 500_000, 0.0), 500_000);
+    }
+}
@@ -2,12 +2,25 @@ use crate::database::read_write::TypedReadStatement;
 use crate::database::Connection;
 use chrono::{DateTime, Local, NaiveDateTime};
 use common::{Country, Gender, Platform};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::time::SystemTime;
 use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres::{Row, Transaction};
 
 pub(crate) struct Queries {
     insert_stmt: tokio_postgres::Statement,
-    query_stmt: [TypedReadStatement; 1 << 5],
+    lock_stmt: tokio_postgres::Statement,
+    duplicate_stmt: tokio_postgres::Statement,
+    select_stmt: tokio_postgres::Statement,
+    update_stmt: tokio_postgres::Statement,
+    /// indexed by filters, [`Sort`], and whether to start after an [`After`]
+    query_stmt: [[[TypedReadStatement; 2]; Sort::ALL.len()]; 1 << 5],
+    house_stmt: TypedReadStatement,
+    changed_stmt: TypedReadStatement,
+    targeted_stmt: TypedReadStatement,
+    version_stmt: TypedReadStatement,
+    landing_stmt: TypedReadStatement,
 }
 
 impl Queries {
@@ -18,8 +31,12 @@ impl Queries {
         tracing::info!("prepare insert statement");
         let insert_stmt = write_conn
             .prepare_typed(
-                r#"INSERT INTO advertisement (title, age_range, country, platform, gender, end_at)
-                VALUES ($1, Int4Range($2, $3), $4,$5, $6, $7);"#,
+                r#"INSERT INTO advertisement (title, age_range, country, platform, gender, end_at, status, advertiser,
+                priority, weight, bid, total_budget, daily_budget, impression_cap, daily_impression_cap, bid_type,
+                category, house, landing_url, campaign)
+                VALUES ($1, Int4Range($2, $3), $4,$5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18,
+                $19, $20, $21)
+                RETURNING id;"#,
                 &[
                     Type::TEXT,
                     Type::INT4,
@@ -28,15 +45,102 @@ impl Queries {
                     Type::INT4,
                     Type::INT4,
                     Type::TIMESTAMP,
+                    Type::INT4,
+                    Type::VARCHAR,
+                    Type::INT4,
+                    Type::INT4,
+                    Type::INT8,
+                    Type::INT8,
+                    Type::INT8,
+                    Type::INT8,
+                    Type::INT8,
+                    Type::INT4,
+                    Type::VARCHAR,
+                    Type::BOOL,
+                    Type::TEXT,
+                    Type::VARCHAR,
+                ],
+            )
+            .await?;
+
+        tracing::info!("prepare duplicate statement");
+        // serialize inserts of the same title, so concurrent duplicates see each other
+        let lock_stmt = write_conn
+            .prepare_typed("SELECT pg_advisory_xact_lock(hashtext($1));", &[Type::TEXT])
+            .await?;
+        let duplicate_stmt = write_conn
+            .prepare_typed(
+                r#"SELECT id FROM advertisement
+                WHERE title = $1 AND age_range = Int4Range($2, $3) AND country = $4
+                AND platform IS NOT DISTINCT FROM $5 AND gender IS NOT DISTINCT FROM $6
+                AND end_at = $7 AND advertiser IS NOT DISTINCT FROM $8 AND status <> $9
+                ORDER BY id LIMIT 1;"#,
+                &[
+                    Type::TEXT,
+                    Type::INT4,
+                    Type::INT4,
+                    Type::INT4,
+                    Type::INT4,
+                    Type::INT4,
+                    Type::TIMESTAMP,
+                    Type::VARCHAR,
+                    Type::INT4,
+                ],
+            )
+            .await?;
+
+        tracing::info!("prepare select statement");
+        let select_stmt = write_conn
+            .prepare_typed(
+                r#"SELECT title, lower(age_range), upper(age_range), country, platform, gender, end_at, status,
+                advertiser, priority, weight, bid, total_budget, daily_budget, impression_cap, daily_impression_cap,
+                bid_type, category, house, landing_url, campaign FROM advertisement WHERE id = $1 FOR UPDATE;"#,
+                &[Type::INT4],
+            )
+            .await?;
+
+        tracing::info!("prepare update statement");
+        let update_stmt = write_conn
+            .prepare_typed(
+                r#"UPDATE advertisement SET title = $2, age_range = Int4Range($3, $4), country = $5,
+                platform = $6, gender = $7, end_at = $8, status = $9, advertiser = $10, priority = $11,
+                weight = $12, bid = $13, total_budget = $14, daily_budget = $15, impression_cap = $16,
+                daily_impression_cap = $17, bid_type = $18, category = $19, house = $20, landing_url = $21,
+                campaign = $22, updated_at = now() WHERE id = $1;"#,
+                &[
+                    Type::INT4,
+                    Type::TEXT,
+                    Type::INT4,
+                    Type::INT4,
+                    Type::INT4,
+                    Type::INT4,
+                    Type::INT4,
+                    Type::TIMESTAMP,
+                    Type::INT4,
+                    Type::VARCHAR,
+                    Type::INT4,
+                    Type::INT4,
+                    Type::INT8,
+                    Type::INT8,
+                    Type::INT8,
+                    Type::INT8,
+                    Type::INT8,
+                    Type::INT4,
+                    Type::VARCHAR,
+                    Type::BOOL,
+                    Type::TEXT,
+                    Type::VARCHAR,
                 ],
             )
             .await?;
 
-        println!("prepare query statement");
+        tracing::debug!("prepare query statement");
         let mut query_stmt = std::array::from_fn(|_| None);
-        for i in 0..1 << 5 {
-            let mut query = "SELECT id, title, end_at FROM advertisement".to_string();
-            let mut filters = Vec::new();
+        for (i, stmt) in query_stmt.iter_mut().enumerate() {
+            let mut filters = vec![
+                format!("status = {}", Status::Active as i32),
+                "NOT house".to_string(),
+            ];
             let mut types = Vec::new();
             let mut n = 1;
 
@@ -59,24 +163,85 @@ impl Queries {
                 filters.push("end_at > now()".to_string());
             }
             if i & 16 != 0 {
-                filters.push(format!("${} = c", n));
+                filters.push(format!("gender = ${}", n));
                 types.push(Type::INT4);
                 n += 1;
             }
 
-            if !filters.is_empty() {
-                query.push_str(" WHERE ");
-                query.push_str(&filters.join(" AND "));
-            }
-            types.push(Type::INT8);
-            types.push(Type::INT8);
-            query.push_str(format!(" ORDER BY id LIMIT ${} OFFSET ${}", n, n + 1).as_str());
-            query_stmt[i] = Some(TypedReadStatement::new(query, types.into_iter()));
+            *stmt = Some(Sort::ALL.map(|sort| {
+                [false, true].map(|after| {
+                    let mut filters = filters.clone();
+                    let mut types = types.clone();
+                    let mut n = n;
+                    if after {
+                        filters.push(sort.after(n));
+                        types.push(sort.key_type());
+                        types.push(Type::INT4);
+                        n += 2;
+                    }
+                    types.push(Type::INT8);
+                    types.push(Type::INT8);
+                    TypedReadStatement::new(
+                        format!(
+                            "SELECT {} FROM advertisement WHERE {} ORDER BY {} LIMIT ${} OFFSET ${}",
+                            PARTIAL_COLUMNS,
+                            filters.join(" AND "),
+                            sort.order_by(),
+                            n,
+                            n + 1
+                        ),
+                        types.into_iter(),
+                    )
+                })
+            }));
         }
         let query_stmt = query_stmt.map(|stmt| stmt.unwrap());
+        // a house advertisement without a country or platform is a fallback for any of them
+        let house_stmt = TypedReadStatement::new(
+            format!(
+                r#"SELECT {} FROM advertisement WHERE status = {} AND house AND end_at > now()
+                AND ($1::int4 IS NULL OR coalesce(country, 0) IN (0, $1))
+                AND ($2::int4 IS NULL OR platform IS NULL OR platform = $2)
+                ORDER BY priority DESC, id LIMIT $3"#,
+                PARTIAL_COLUMNS,
+                Status::Active as i32
+            ),
+            [Type::INT4, Type::INT4, Type::INT8].into_iter(),
+        );
+        let changed_stmt = TypedReadStatement::new(
+            format!(
+                "SELECT {} FROM advertisement WHERE updated_at > $1 ORDER BY updated_at",
+                TARGETED_COLUMNS,
+            ),
+            [Type::TIMESTAMP].into_iter(),
+        );
+        let targeted_stmt = TypedReadStatement::new(
+            format!(
+                "SELECT {} FROM advertisement WHERE id = ANY($1)",
+                TARGETED_COLUMNS,
+            ),
+            [Type::INT4_ARRAY].into_iter(),
+        );
+        let version_stmt = TypedReadStatement::new(
+            "SELECT last_value FROM advertisement_version",
+            [].into_iter(),
+        );
+        let landing_stmt = TypedReadStatement::new(
+            "SELECT landing_url FROM advertisement WHERE id = $1",
+            [Type::INT4].into_iter(),
+        );
         Ok(Queries {
             insert_stmt,
+            lock_stmt,
+            duplicate_stmt,
+            select_stmt,
+            update_stmt,
             query_stmt,
+            house_stmt,
+            changed_stmt,
+            targeted_stmt,
+            version_stmt,
+            landing_stmt,
         })
     }
     fn get_query_stmt(
@@ -85,8 +250,11 @@ impl Queries {
         platform: bool,
         age: bool,
         gender: bool,
+        sort: Sort,
+        after: bool,
     ) -> &TypedReadStatement {
-        let mut idx = 0;
+        // expired advertisements are never served
+        let mut idx = 1 << 3;
         if country {
             idx |= 1;
         }
@@ -99,20 +267,100 @@ impl Queries {
         if gender {
             idx |= 1 << 4;
         }
-        &self.query_stmt[idx]
+        &self.query_stmt[idx][sort as usize][after as usize]
     }
 }
 
 impl Queries {
+    /// insert the advertisement unless a live one with the same advertiser, title,
+    /// targeting and schedule exists
     pub async fn insert(
         &self,
         advertisement: &Advertisement,
-        write: &Connection<'_>,
+        write: &Transaction<'_>,
+    ) -> Result<Insert, tokio_postgres::Error> {
+        let country = advertisement
+            .country
+            .clone()
+            .map(|x| x.into_id() as i32)
+            .unwrap_or_default();
+        let platform = advertisement.platform.map(|p| p as i32);
+        let gender = advertisement.gender.clone().map(|g| g as i32);
+        let end_at = SystemTime::from(advertisement.end_at.and_utc());
+
+        write
+            .execute(&self.lock_stmt, &[&advertisement.title])
+            .await?;
+        let duplicate = write
+            .query_opt(
+                &self.duplicate_stmt,
+                &[
+                    &advertisement.title,
+                    &(advertisement.age_range.0),
+                    &(advertisement.age_range.1),
+                    &country,
+                    &platform,
+                    &gender,
+                    &end_at,
+                    &advertisement.advertiser,
+                    &(Status::Archived as i32),
+                ],
+            )
+            .await?;
+        if let Some(row) = duplicate {
+            return Ok(Insert::Duplicate(row.get(0)));
+        }
+
+        let row = write
+            .query_one(
+                &self.insert_stmt,
+                &[
+                    &advertisement.title,
+                    &(advertisement.age_range.0),
+                    &(advertisement.age_range.1),
+                    &country,
+                    &platform,
+                    &gender,
+                    &end_at,
+                    &(advertisement.status as i32),
+                    &advertisement.advertiser,
+                    &advertisement.priority,
+                    &advertisement.weight,
+                    &advertisement.bid,
+                    &advertisement.budget.total_budget,
+                    &advertisement.budget.daily_budget,
+                    &advertisement.budget.impression_cap,
+                    &advertisement.budget.daily_impression_cap,
+                    &(advertisement.bid_type as i32),
+                    &advertisement.category,
+                    &advertisement.house,
+                    &advertisement.landing_url,
+                    &advertisement.campaign,
+                ],
+            )
+            .await?;
+        Ok(Insert::Inserted(row.get(0)))
+    }
+    /// lock the advertisement row until the transaction ends
+    pub async fn select_for_update(
+        &self,
+        id: i32,
+        write: &Transaction<'_>,
+    ) -> Result<Option<Advertisement>, tokio_postgres::Error> {
+        let row = write.query_opt(&self.select_stmt, &[&id]).await?;
+        Ok(row.map(|row| Advertisement::from_row(&row)))
+    }
+    pub async fn update(
+        &self,
+        id: i32,
+        advertisement: &Advertisement,
+        write: &Transaction<'_>,
     ) -> Result<(), tokio_postgres::Error> {
         write
             .execute(
-                &self.insert_stmt,
+                &self.update_stmt,
                 &[
+                    &id,
                     &advertisement.title,
                     &(advertisement.age_range.0),
                     &(advertisement.age_range.1),
@@ -121,9 +369,23 @@ impl Queries {
                         .clone()
                         .map(|x| x.into_id() as i32)
                         .unwrap_or_default(),
-                    &advertisement.platform.clone().map(|p| p as i32),
+                    &advertisement.platform.map(|p| p as i32),
                     &advertisement.gender.clone().map(|g| g as i32),
                     &SystemTime::from(advertisement.end_at.and_utc()),
+                    &(advertisement.status as i32),
+                    &advertisement.advertiser,
+                    &advertisement.priority,
+                    &advertisement.weight,
+                    &advertisement.bid,
+                    &advertisement.budget.total_budget,
+                    &advertisement.budget.daily_budget,
+                    &advertisement.budget.impression_cap,
+                    &advertisement.budget.daily_impression_cap,
+                    &(advertisement.bid_type as i32),
+                    &advertisement.category,
+                    &advertisement.house,
+                    &advertisement.landing_url,
+                    &advertisement.campaign,
                 ],
             )
             .await?;
@@ -133,6 +395,8 @@ impl Queries {
         &self,
         read: &Connection<'_>,
         cond: Condition,
+        sort: Sort,
+        after: Option<After>,
         (limit, offset): (usize, usize),
     ) -> Result<Vec<PartialAdvertisement>, tokio_postgres::Error> {
         let stmt = self.get_query_stmt(
@@ -140,6 +404,8 @@ impl Queries {
             cond.platform.is_some(),
             cond.age.is_some(),
             cond.gender.is_some(),
+            sort,
+            after.is_some(),
         );
         let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
 
@@ -167,37 +433,393 @@ impl Queries {
             params.push(&gender);
         }
 
+        let (priority, end_at, id);
+        if let Some(x) = after {
+            match sort {
+                Sort::PriorityDesc | Sort::Priority => {
+                    priority = x.priority;
+                    params.push(&priority);
+                }
+                Sort::EndAt | Sort::EndAtDesc => {
+                    end_at = SystemTime::from(x.end_at.and_utc());
+                    params.push(&end_at);
+                }
+            }
+            id = x.id;
+            params.push(&id);
+        }
+
         let limit = &(limit as i64);
         let offset = &(offset as i64);
         params.push(limit);
         params.push(offset);
 
-        let rows = stmt.query(&read, params.into_iter()).await?;
+        let rows = stmt.query(read, params.into_iter()).await?;
+
+        Ok(rows.iter().map(PartialAdvertisement::from_row).collect())
+    }
+    /// active house advertisements for `country` and `platform`, to fill a response without paid ones
+    pub async fn query_house(
+        &self,
+        read: &Connection<'_>,
+        country: Option<Country>,
+        platform: Option<Platform>,
+        limit: usize,
+    ) -> Result<Vec<PartialAdvertisement>, tokio_postgres::Error> {
+        let country = country.map(|x| x.into_id() as i32);
+        let platform = platform.map(|x| x as i32);
+        let limit = limit as i64;
+        let rows = self
+            .house_stmt
+            .query(
+                read,
+                [
+                    &country as &(dyn ToSql + Sync),
+                    &platform as &(dyn ToSql + Sync),
+                    &limit as &(dyn ToSql + Sync),
+                ]
+                .into_iter(),
+            )
+            .await?;
 
-        Ok(rows
-            .iter()
-            .map(|row| PartialAdvertisement {
-                id: row.get(0),
-                title: row.get(1),
-                end_at: DateTime::<Local>::from(row.get::<_, SystemTime>(2)).naive_utc(),
-            })
-            .collect())
+        Ok(rows.iter().map(PartialAdvertisement::from_row).collect())
+    }
+    /// every advertisement inserted or updated after `since`, whatever its status
+    pub async fn changed_since(
+        &self,
+        read: &Connection<'_>,
+        since: NaiveDateTime,
+    ) -> Result<Vec<Targeted>, tokio_postgres::Error> {
+        let since = SystemTime::from(since.and_utc());
+        let rows = self
+            .changed_stmt
+            .query(read, [&since as &(dyn ToSql + Sync)].into_iter())
+            .await?;
+
+        Ok(rows.iter().map(Targeted::from_row).collect())
+    }
+    /// the advertisements `ids` as they are now, whatever their status
+    ///
+    /// Run on the write connection by callers that must not read a lagging replica.
+    pub async fn targeted(
+        &self,
+        conn: &Connection<'_>,
+        ids: &[i32],
+    ) -> Result<Vec<Targeted>, tokio_postgres::Error> {
+        let rows = self
+            .targeted_stmt
+            .query(conn, [&ids as &(dyn ToSql + Sync)].into_iter())
+            .await?;
+
+        Ok(rows.iter().map(Targeted::from_row).collect())
+    }
+    /// `None` when the advertisement does not exist or has no landing URL
+    pub async fn landing_url(
+        &self,
+        read: &Connection<'_>,
+        id: i32,
+    ) -> Result<Option<String>, tokio_postgres::Error> {
+        let rows = self
+            .landing_stmt
+            .query(read, [&id as &(dyn ToSql + Sync)].into_iter())
+            .await?;
+        Ok(rows.first().and_then(|row| row.get(0)))
+    }
+    /// latest version notified with a change, see `migration/create_table.sql`
+    pub async fn version(&self, conn: &Connection<'_>) -> Result<i64, tokio_postgres::Error> {
+        let rows = self.version_stmt.query(conn, [].into_iter()).await?;
+        Ok(rows[0].get(0))
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Advertisement {
     pub title: String,
     pub age_range: (i32, i32), // int4range
+    /// alpha-2 in revision snapshots, which are read back on rollback
+    #[serde(with = "common::alpha2")]
     pub country: Option<Country>,
     pub platform: Option<Platform>,
     pub gender: Option<Gender>,
     pub end_at: NaiveDateTime,
+    pub status: Status,
+    pub advertiser: Option<String>,
+    /// higher priority is served first
+    #[serde(default)]
+    pub priority: i32,
+    /// share of traffic among the same priority under weighted rotation
+    #[serde(default = "default_weight")]
+    pub weight: i32,
+    /// price per thousand impressions, or per click under [`BidType::Cpc`], in micros
+    #[serde(default)]
+    pub bid: i64,
+    #[serde(default)]
+    pub bid_type: BidType,
+    #[serde(flatten)]
+    pub budget: Budget,
+    /// kept apart from competing categories in a response, see [`crate::separation`]
+    #[serde(default)]
+    pub category: Option<String>,
+    /// served unpaid, only when no other advertisement matches
+    #[serde(default)]
+    pub house: bool,
+    /// where `GET /track/click` redirects, after expanding macros such as `{ad_id}`
+    #[serde(default)]
+    pub landing_url: Option<String>,
+    /// groups advertisements in reports
+    #[serde(default)]
+    pub campaign: Option<String>,
+}
+
+fn default_weight() -> i32 {
+    1
+}
+
+impl Advertisement {
+    fn from_row(row: &Row) -> Self {
+        Self {
+            title: row.get(0),
+            age_range: (row.get(1), row.get(2)),
+            country: row
+                .get::<_, Option<i32>>(3)
+                .and_then(|x| Country::from_id(x as u32)),
+            platform: row.get::<_, Option<i32>>(4).and_then(|x| x.try_into().ok()),
+            gender: row.get::<_, Option<i32>>(5).and_then(|x| x.try_into().ok()),
+            end_at: DateTime::<Local>::from(row.get::<_, SystemTime>(6)).naive_utc(),
+            status: row.get::<_, i32>(7).try_into().unwrap_or_default(),
+            advertiser: row.get(8),
+            priority: row.get(9),
+            weight: row.get(10),
+            bid: row.get(11),
+            budget: Budget {
+                total_budget: row.get(12),
+                daily_budget: row.get(13),
+                impression_cap: row.get(14),
+                daily_impression_cap: row.get(15),
+            },
+            bid_type: row.get::<_, i32>(16).try_into().unwrap_or_default(),
+            category: row.get(17),
+            house: row.get(18),
+            landing_url: row.get(19),
+            campaign: row.get(20),
+        }
+    }
 }
 
+/// order of served advertisements, ties are broken by id so that pages are stable
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum Sort {
+    #[default]
+    #[serde(rename = "-priority")]
+    PriorityDesc = 0,
+    #[serde(rename = "priority")]
+    Priority = 1,
+    #[serde(rename = "end_at")]
+    EndAt = 2,
+    #[serde(rename = "-end_at")]
+    EndAtDesc = 3,
+}
+
+impl Sort {
+    /// indexed by discriminant
+    pub const ALL: [Sort; 4] = [
+        Sort::PriorityDesc,
+        Sort::Priority,
+        Sort::EndAt,
+        Sort::EndAtDesc,
+    ];
+    /// filter for rows strictly after a position, binding the sort key at `$n` and id at `$n+1`
+    fn after(self, n: usize) -> String {
+        let (column, cmp) = match self {
+            Sort::PriorityDesc => ("priority", "<"),
+            Sort::Priority => ("priority", ">"),
+            Sort::EndAt => ("end_at", ">"),
+            Sort::EndAtDesc => ("end_at", "<"),
+        };
+        format!(
+            "({column} {cmp} ${n} OR ({column} = ${n} AND id > ${}))",
+            n + 1
+        )
+    }
+    fn key_type(self) -> Type {
+        match self {
+            Sort::PriorityDesc | Sort::Priority => Type::INT4,
+            Sort::EndAt | Sort::EndAtDesc => Type::TIMESTAMP,
+        }
+    }
+    /// order of two positions, matching [`Sort::order_by`]
+    pub fn compare(self, a: &After, b: &After) -> Ordering {
+        let key = match self {
+            Sort::PriorityDesc => b.priority.cmp(&a.priority),
+            Sort::Priority => a.priority.cmp(&b.priority),
+            Sort::EndAt => a.end_at.cmp(&b.end_at),
+            Sort::EndAtDesc => b.end_at.cmp(&a.end_at),
+        };
+        key.then(a.id.cmp(&b.id))
+    }
+    fn order_by(self) -> &'static str {
+        match self {
+            Sort::PriorityDesc => "priority DESC, id",
+            Sort::Priority => "priority, id",
+            Sort::EndAt => "end_at, id",
+            Sort::EndAtDesc => "end_at DESC, id",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[repr(i32)]
+pub enum BidType {
+    #[default]
+    Cpm = 1,
+    Cpc = 2,
+}
+
+impl TryFrom<i32> for BidType {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(BidType::Cpm),
+            2 => Ok(BidType::Cpc),
+            x => Err(x),
+        }
+    }
+}
+
+/// delivery limits, spend in micros, `None` is unlimited
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Budget {
+    #[serde(default)]
+    pub total_budget: Option<i64>,
+    #[serde(default)]
+    pub daily_budget: Option<i64>,
+    #[serde(default)]
+    pub impression_cap: Option<i64>,
+    #[serde(default)]
+    pub daily_impression_cap: Option<i64>,
+}
+
+/// keyset position of the last served advertisement
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct After {
+    pub priority: i32,
+    pub end_at: NaiveDateTime,
+    pub id: i32,
+}
+
+pub enum Insert {
+    Inserted(i32),
+    /// id of the existing advertisement
+    Duplicate(i32),
+}
+
+/// only [`Status::Active`] advertisements are served
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[repr(i32)]
+pub enum Status {
+    #[default]
+    Active = 1,
+    Paused = 2,
+    Archived = 3,
+}
+
+impl TryFrom<i32> for Status {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Status::Active),
+            2 => Ok(Status::Paused),
+            3 => Ok(Status::Archived),
+            x => Err(x),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct PartialAdvertisement {
     pub id: i32,
     pub title: String,
     pub end_at: NaiveDateTime,
+    pub priority: i32,
+    pub weight: i32,
+    pub bid: i64,
+    pub budget: Budget,
+    pub bid_type: BidType,
+    pub advertiser: Option<String>,
+    pub category: Option<String>,
+}
+
+/// an advertisement with its targeting, as kept by [`crate::index`]
+pub struct Targeted {
+    pub partial: PartialAdvertisement,
+    /// lower and upper bound, unbounded when `None`; matches no age when the range is `None`
+    pub age_range: Option<(Option<i32>, Option<i32>)>,
+    /// 0 when unset, stored as 0 or NULL
+    pub country: i32,
+    pub platform: Option<i32>,
+    pub gender: Option<i32>,
+    pub status: Status,
+    pub house: bool,
+    pub updated_at: NaiveDateTime,
+}
+
+/// columns read by [`Targeted::from_row`]
+const TARGETED_COLUMNS: &str = r#"id, title, end_at, priority, weight, bid, total_budget, daily_budget,
+            impression_cap, daily_impression_cap, bid_type, advertiser, category, lower(age_range),
+            upper(age_range), coalesce(isempty(age_range), true), country, platform, gender, status, house,
+            updated_at"#;
+
+impl Targeted {
+    fn from_row(row: &Row) -> Self {
+        Self {
+            partial: PartialAdvertisement::from_row(row),
+            age_range: (!row.get::<_, bool>(15)).then(|| (row.get(13), row.get(14))),
+            country: row.get::<_, Option<i32>>(16).unwrap_or_default(),
+            platform: row.get(17),
+            gender: row.get(18),
+            status: row.get::<_, i32>(19).try_into().unwrap_or(Status::Archived),
+            house: row.get(20),
+            updated_at: DateTime::<Local>::from(row.get::<_, SystemTime>(21)).naive_utc(),
+        }
+    }
+}
+
+/// columns read by [`PartialAdvertisement::from_row`], a prefix of [`TARGETED_COLUMNS`]
+const PARTIAL_COLUMNS: &str = r#"id, title, end_at, priority, weight, bid, total_budget, daily_budget,
+            impression_cap, daily_impression_cap, bid_type, advertiser, category"#;
+
+impl PartialAdvertisement {
+    /// keyset position of the advertisement
+    pub fn position(&self) -> After {
+        After {
+            priority: self.priority,
+            end_at: self.end_at,
+            id: self.id,
+        }
+    }
+    fn from_row(row: &Row) -> Self {
+        Self {
+            id: row.get(0),
+            title: row.get(1),
+            end_at: DateTime::<Local>::from(row.get::<_, SystemTime>(2)).naive_utc(),
+            priority: row.get(3),
+            weight: row.get(4),
+            bid: row.get(5),
+            budget: Budget {
+                total_budget: row.get(6),
+                daily_budget: row.get(7),
+                impression_cap: row.get(8),
+                daily_impression_cap: row.get(9),
+            },
+            bid_type: row.get::<_, i32>(10).try_into().unwrap_or_default(),
+            advertiser: row.get(11),
+            category: row.get(12),
+        }
+    }
 }
 
 pub struct Condition {
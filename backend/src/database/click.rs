@@ -0,0 +1,65 @@
+use crate::database::event::Event;
+use crate::database::Impression;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::SystemTime;
+use tokio_postgres::types::{ToSql, Type};
+
+/// a click on a served advertisement
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Click {
+    /// derived from the served advertisement, so that repeated clicks share it
+    pub id: i64,
+    pub served: Impression,
+}
+
+impl Click {
+    pub fn new(served: Impression) -> Self {
+        let hash = Sha256::new()
+            .chain_update(&served.request_id)
+            .chain_update(served.advertisement_id.to_be_bytes())
+            .finalize();
+        let id = i64::from_be_bytes(hash[..8].try_into().unwrap()) & i64::MAX;
+        Self { id, served }
+    }
+}
+
+/// recorded once per served advertisement, see the unique key of `click`
+impl Event for Click {
+    const TABLE: &'static str = "click";
+    const KEY: &'static [&'static str] = &["request_id", "advertisement_id"];
+    const COLUMNS: &'static [(&'static str, Type)] = &[
+        ("id", Type::INT8),
+        ("advertisement_id", Type::INT4),
+        ("request_id", Type::VARCHAR),
+        ("experiment_id", Type::INT4),
+        ("user_id", Type::VARCHAR),
+        ("age", Type::INT4),
+        ("country", Type::INT4),
+        ("platform", Type::INT4),
+        ("gender", Type::INT4),
+        ("placement", Type::VARCHAR),
+        ("served_at", Type::TIMESTAMP),
+        ("cost", Type::INT8),
+        ("invalid", Type::INT4),
+    ];
+    fn values(&self) -> Vec<Box<dyn ToSql + Sync + Send>> {
+        let served = &self.served;
+        let (country, platform, gender) = served.targeting_ids();
+        vec![
+            Box::new(self.id),
+            Box::new(served.advertisement_id),
+            Box::new(served.request_id.clone()),
+            Box::new(served.experiment_id),
+            Box::new(served.user_id.clone()),
+            Box::new(served.age),
+            Box::new(country),
+            Box::new(platform),
+            Box::new(gender),
+            Box::new(served.placement.clone()),
+            Box::new(SystemTime::from(served.served_at.and_utc())),
+            Box::new(served.click_cost),
+            Box::new(served.invalid.map(|x| x as i32)),
+        ]
+    }
+}
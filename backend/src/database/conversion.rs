@@ -0,0 +1,150 @@
+use crate::database::Connection;
+use std::time::Duration;
+use tokio_postgres::types::Type;
+
+pub(crate) struct Queries {
+    click_stmt: tokio_postgres::Statement,
+    user_click_stmt: tokio_postgres::Statement,
+    insert_stmt: tokio_postgres::Statement,
+    existing_stmt: tokio_postgres::Statement,
+}
+
+impl Queries {
+    pub async fn new(
+        _: &Connection<'_>,
+        write_conn: &Connection<'_>,
+    ) -> Result<Self, tokio_postgres::Error> {
+        tracing::info!("prepare conversion statement");
+        // invalid clicks are never credited
+        let click_stmt = write_conn
+            .prepare_typed(
+                r#"SELECT id, advertisement_id, experiment_id FROM click
+                WHERE id = $1 AND invalid IS NULL AND created_at > now() - make_interval(secs => $2);"#,
+                &[Type::INT8, Type::FLOAT8],
+            )
+            .await?;
+        // the last click of the user is credited
+        let user_click_stmt = write_conn
+            .prepare_typed(
+                r#"SELECT id, advertisement_id, experiment_id FROM click
+                WHERE user_id = $1 AND advertisement_id = $2 AND invalid IS NULL
+                AND created_at > now() - make_interval(secs => $3)
+                ORDER BY created_at DESC LIMIT 1;"#,
+                &[Type::VARCHAR, Type::INT4, Type::FLOAT8],
+            )
+            .await?;
+        let insert_stmt = write_conn
+            .prepare_typed(
+                r#"INSERT INTO conversion (click_id, advertisement_id, transaction_id, value)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (click_id, transaction_id) DO NOTHING RETURNING id;"#,
+                &[Type::INT8, Type::INT4, Type::VARCHAR, Type::INT8],
+            )
+            .await?;
+        let existing_stmt = write_conn
+            .prepare_typed(
+                "SELECT id FROM conversion WHERE click_id = $1 AND transaction_id = $2;",
+                &[Type::INT8, Type::VARCHAR],
+            )
+            .await?;
+
+        Ok(Queries {
+            click_stmt,
+            user_click_stmt,
+            insert_stmt,
+            existing_stmt,
+        })
+    }
+}
+
+impl Queries {
+    /// record the conversion against the click it follows, clicked within `lookback`
+    pub async fn attribute(
+        &self,
+        write: &Connection<'_>,
+        postback: &Postback,
+        lookback: Duration,
+    ) -> Result<Attribution, tokio_postgres::Error> {
+        let lookback = lookback.as_secs_f64();
+        let click = match (
+            postback.click_id,
+            &postback.user_id,
+            postback.advertisement_id,
+        ) {
+            (Some(click_id), _, _) => {
+                write
+                    .query_opt(&self.click_stmt, &[&click_id, &lookback])
+                    .await?
+            }
+            (None, Some(user_id), Some(advertisement_id)) => {
+                write
+                    .query_opt(
+                        &self.user_click_stmt,
+                        &[user_id, &advertisement_id, &lookback],
+                    )
+                    .await?
+            }
+            _ => None,
+        };
+        let Some(click) = click else {
+            return Ok(Attribution::Unattributed);
+        };
+
+        let mut conversion = Conversion {
+            id: 0,
+            click_id: click.get(0),
+            advertisement_id: click.get(1),
+            experiment_id: click.get(2),
+        };
+        let transaction_id = postback.transaction_id.as_deref().unwrap_or_default();
+        let inserted = write
+            .query_opt(
+                &self.insert_stmt,
+                &[
+                    &conversion.click_id,
+                    &conversion.advertisement_id,
+                    &transaction_id,
+                    &postback.value,
+                ],
+            )
+            .await?;
+        if let Some(row) = inserted {
+            conversion.id = row.get(0);
+            return Ok(Attribution::Converted(conversion));
+        }
+        let existing = write
+            .query_one(
+                &self.existing_stmt,
+                &[&conversion.click_id, &transaction_id],
+            )
+            .await?;
+        conversion.id = existing.get(0);
+        Ok(Attribution::Duplicate(conversion))
+    }
+}
+
+/// a conversion reported by an advertiser, by click id or by user and advertisement
+pub struct Postback {
+    pub click_id: Option<i64>,
+    pub user_id: Option<String>,
+    pub advertisement_id: Option<i32>,
+    /// the same transaction reported again is a duplicate
+    pub transaction_id: Option<String>,
+    /// in micros
+    pub value: i64,
+}
+
+pub struct Conversion {
+    pub id: i64,
+    pub click_id: i64,
+    pub advertisement_id: i32,
+    pub experiment_id: Option<i32>,
+}
+
+pub enum Attribution {
+    Converted(Conversion),
+    /// the conversion recorded by an earlier postback
+    Duplicate(Conversion),
+    /// no click within the lookback window
+    Unattributed,
+}
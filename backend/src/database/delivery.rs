@@ -0,0 +1,131 @@
+use crate::database::Connection;
+use chrono::{DateTime, Local, NaiveDateTime};
+use std::collections::HashMap;
+use std::time::SystemTime;
+use tokio_postgres::types::{ToSql, Type};
+
+pub(crate) struct Queries {
+    record_stmt: tokio_postgres::Statement,
+    total_stmt: tokio_postgres::Statement,
+    usage_stmt: tokio_postgres::Statement,
+}
+
+impl Queries {
+    pub async fn new(
+        _: &Connection<'_>,
+        write_conn: &Connection<'_>,
+    ) -> Result<Self, tokio_postgres::Error> {
+        tracing::info!("prepare delivery statement");
+        // days are counted in UTC, matching the pacing in `crate::delivery`;
+        // ids without an advertisement, e.g. from a forged click, are skipped instead of failing the batch
+        let record_stmt = write_conn
+            .prepare_typed(
+                r#"INSERT INTO delivery_counter (advertisement_id, day, impressions, spend, clicks)
+                SELECT id, (now() AT TIME ZONE 'utc')::date, $2, $3, $4 FROM advertisement WHERE id = $1
+                ON CONFLICT (advertisement_id, day) DO UPDATE
+                SET impressions = delivery_counter.impressions + EXCLUDED.impressions,
+                spend = delivery_counter.spend + EXCLUDED.spend,
+                clicks = delivery_counter.clicks + EXCLUDED.clicks;"#,
+                &[Type::INT4, Type::INT8, Type::INT8, Type::INT8],
+            )
+            .await?;
+        let total_stmt = write_conn
+            .prepare_typed(
+                r#"INSERT INTO delivery_total (advertisement_id, impressions, spend, clicks)
+                SELECT id, $2, $3, $4 FROM advertisement WHERE id = $1
+                ON CONFLICT (advertisement_id) DO UPDATE
+                SET impressions = delivery_total.impressions + EXCLUDED.impressions,
+                spend = delivery_total.spend + EXCLUDED.spend,
+                clicks = delivery_total.clicks + EXCLUDED.clicks, updated_at = now();"#,
+                &[Type::INT4, Type::INT8, Type::INT8, Type::INT8],
+            )
+            .await?;
+        // read from the write host, a replica lagging behind a flush would hide its increments;
+        // only the totals changed since `$1` are read, with the row of today
+        let usage_stmt = write_conn
+            .prepare_typed(
+                r#"SELECT delivery_total.advertisement_id, delivery_total.impressions,
+                delivery_total.spend, coalesce(delivery_counter.impressions, 0),
+                coalesce(delivery_counter.spend, 0), delivery_total.clicks, delivery_total.updated_at
+                FROM delivery_total LEFT JOIN delivery_counter
+                ON delivery_counter.advertisement_id = delivery_total.advertisement_id
+                AND delivery_counter.day = (now() AT TIME ZONE 'utc')::date
+                WHERE delivery_total.updated_at > $1;"#,
+                &[Type::TIMESTAMP],
+            )
+            .await?;
+
+        Ok(Queries {
+            record_stmt,
+            total_stmt,
+            usage_stmt,
+        })
+    }
+}
+
+impl Queries {
+    pub async fn record(
+        &self,
+        write: &mut Connection<'_>,
+        usage: &HashMap<i32, Usage>,
+    ) -> Result<(), tokio_postgres::Error> {
+        let tx = write.transaction().await?;
+        for (id, usage) in usage {
+            let params: [&(dyn ToSql + Sync); 4] =
+                [id, &usage.impressions, &usage.spend, &usage.clicks];
+            tx.execute(&self.record_stmt, &params).await?;
+            tx.execute(&self.total_stmt, &params).await?;
+        }
+        tx.commit().await
+    }
+    /// usage of the advertisements delivered since `since`, with the latest change read
+    pub async fn usage(
+        &self,
+        write: &Connection<'_>,
+        since: NaiveDateTime,
+    ) -> Result<(HashMap<i32, Usage>, NaiveDateTime), tokio_postgres::Error> {
+        let rows = write
+            .query(&self.usage_stmt, &[&SystemTime::from(since.and_utc())])
+            .await?;
+
+        let mut latest = since;
+        let usage = rows
+            .iter()
+            .map(|row| {
+                let updated_at = DateTime::<Local>::from(row.get::<_, SystemTime>(6)).naive_utc();
+                latest = latest.max(updated_at);
+                (
+                    row.get(0),
+                    Usage {
+                        impressions: row.get(1),
+                        spend: row.get(2),
+                        daily_impressions: row.get(3),
+                        daily_spend: row.get(4),
+                        clicks: row.get(5),
+                    },
+                )
+            })
+            .collect();
+        Ok((usage, latest))
+    }
+}
+
+/// delivered impressions, spend and clicks of an advertisement, spend in micros
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub impressions: i64,
+    pub spend: i64,
+    pub daily_impressions: i64,
+    pub daily_spend: i64,
+    pub clicks: i64,
+}
+
+impl Usage {
+    pub fn add(&mut self, other: &Usage) {
+        self.impressions += other.impressions;
+        self.spend += other.spend;
+        self.daily_impressions += other.daily_impressions;
+        self.daily_spend += other.daily_spend;
+        self.clicks += other.clicks;
+    }
+}
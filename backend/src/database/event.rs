@@ -0,0 +1,84 @@
+use crate::database::Connection;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::{ToSql, Type};
+
+/// a row appended in batches by [`crate::events`]
+///
+/// A batch may be copied again after a failure, and a token may be replayed to any
+/// backend, so the table rejects a repeated event with a unique constraint on
+/// [`Event::KEY`]; rejected rows are skipped.
+pub trait Event: Serialize + DeserializeOwned + Send + Sync + 'static {
+    const TABLE: &'static str;
+    /// columns of the unique constraint identifying an event
+    const KEY: &'static [&'static str];
+    /// copied columns with their types
+    const COLUMNS: &'static [(&'static str, Type)];
+    /// values of [`Event::COLUMNS`], in order
+    fn values(&self) -> Vec<Box<dyn ToSql + Sync + Send>>;
+}
+
+/// `COPY` the events into a staging table, then move the new ones into [`Event::TABLE`]
+///
+/// Returns the positions in `events` of those appended, an event repeated within the
+/// batch or recorded before by any backend is left out.
+pub async fn copy<E: Event>(
+    write: &mut Connection<'_>,
+    events: &[E],
+) -> Result<Vec<usize>, tokio_postgres::Error> {
+    let columns = E::COLUMNS
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let key = E::KEY.join(", ");
+    let mut types: Vec<Type> = E::COLUMNS.iter().map(|(_, x)| x.clone()).collect();
+    types.push(Type::INT4);
+    let table = E::TABLE;
+    let staging = format!("{table}_staging");
+
+    let tx = write.transaction().await?;
+    // lives as long as the pooled connection, emptied by every commit; `seq` is the
+    // position of the event in the batch
+    tx.batch_execute(&format!(
+        r#"CREATE TEMP TABLE IF NOT EXISTS {staging} ON COMMIT DELETE ROWS
+        AS SELECT {columns}, 0::int4 AS seq FROM {table} WITH NO DATA;"#
+    ))
+    .await?;
+    let sink = tx
+        .copy_in(&format!(
+            "COPY {staging} ({columns}, seq) FROM STDIN BINARY"
+        ))
+        .await?;
+    let writer = BinaryCopyInWriter::new(sink, &types);
+    tokio::pin!(writer);
+    for (seq, event) in events.iter().enumerate() {
+        let values = event.values();
+        let seq = seq as i32;
+        let row: Vec<&(dyn ToSql + Sync)> = values
+            .iter()
+            .map(|x| x.as_ref() as &(dyn ToSql + Sync))
+            .chain([&seq as &(dyn ToSql + Sync)])
+            .collect();
+        writer.as_mut().write(&row).await?;
+    }
+    writer.finish().await?;
+    // the first of repeated events is the one appended, and reported
+    let rows = tx
+        .query(
+            &format!(
+                r#"WITH appended AS (
+                    INSERT INTO {table} ({columns})
+                    SELECT DISTINCT ON ({key}) {columns} FROM {staging} ORDER BY {key}, seq
+                    ON CONFLICT DO NOTHING RETURNING {key}
+                )
+                SELECT min({staging}.seq) FROM {staging} JOIN appended USING ({key})
+                GROUP BY {key};"#
+            ),
+            &[],
+        )
+        .await?;
+    tx.commit().await?;
+    Ok(rows.iter().map(|x| x.get::<_, i32>(0) as usize).collect())
+}
@@ -0,0 +1,292 @@
+use crate::database::read_write::TypedReadStatement;
+use crate::database::Connection;
+use chrono::{DateTime, Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::SystemTime;
+use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres::Transaction;
+
+pub(crate) struct Queries {
+    insert_stmt: tokio_postgres::Statement,
+    variant_stmt: tokio_postgres::Statement,
+    lock_stmt: tokio_postgres::Statement,
+    overlap_stmt: tokio_postgres::Statement,
+    status_stmt: tokio_postgres::Statement,
+    record_stmt: tokio_postgres::Statement,
+    select_stmt: TypedReadStatement,
+    variants_stmt: TypedReadStatement,
+    running_stmt: TypedReadStatement,
+}
+
+impl Queries {
+    pub async fn new(
+        _: &Connection<'_>,
+        write_conn: &Connection<'_>,
+    ) -> Result<Self, tokio_postgres::Error> {
+        tracing::info!("prepare experiment statement");
+        let insert_stmt = write_conn
+            .prepare_typed(
+                "INSERT INTO experiment (name) VALUES ($1) RETURNING id;",
+                &[Type::VARCHAR],
+            )
+            .await?;
+        let variant_stmt = write_conn
+            .prepare_typed(
+                r#"INSERT INTO experiment_variant (experiment_id, advertisement_id, traffic)
+                VALUES ($1, $2, $3);"#,
+                &[Type::INT4, Type::INT4, Type::INT4],
+            )
+            .await?;
+        // serializes starts, so that two experiments cannot claim the same advertisement concurrently
+        let lock_stmt = write_conn
+            .prepare_typed("SELECT pg_advisory_xact_lock(hashtext('experiment'));", &[])
+            .await?;
+        let overlap_stmt = write_conn
+            .prepare_typed(
+                r#"SELECT EXISTS (SELECT 1 FROM experiment_variant v
+                JOIN experiment e ON e.id = v.experiment_id
+                WHERE e.status = 1 AND e.id <> $1 AND v.advertisement_id IN
+                (SELECT advertisement_id FROM experiment_variant WHERE experiment_id = $1));"#,
+                &[Type::INT4],
+            )
+            .await?;
+        let status_stmt = write_conn
+            .prepare_typed(
+                r#"UPDATE experiment SET status = $2,
+                stopped_at = CASE WHEN $2 = 1 THEN NULL ELSE now() END
+                WHERE id = $1 RETURNING id;"#,
+                &[Type::INT4, Type::INT4],
+            )
+            .await?;
+        let record_stmt = write_conn
+            .prepare_typed(
+                r#"UPDATE experiment_variant
+                SET impressions = impressions + $3, clicks = clicks + $4,
+                conversions = conversions + $5, revenue = revenue + $6
+                WHERE experiment_id = $1 AND advertisement_id = $2;"#,
+                &[
+                    Type::INT4,
+                    Type::INT4,
+                    Type::INT8,
+                    Type::INT8,
+                    Type::INT8,
+                    Type::INT8,
+                ],
+            )
+            .await?;
+        let select_stmt = TypedReadStatement::new(
+            "SELECT id, name, status, created_at, stopped_at FROM experiment WHERE id = $1",
+            [Type::INT4].into_iter(),
+        );
+        let variants_stmt = TypedReadStatement::new(
+            r#"SELECT advertisement_id, traffic, impressions, clicks, conversions, revenue FROM experiment_variant
+            WHERE experiment_id = $1 ORDER BY advertisement_id"#,
+            [Type::INT4].into_iter(),
+        );
+        let running_stmt = TypedReadStatement::new(
+            r#"SELECT v.experiment_id, v.advertisement_id, v.traffic FROM experiment_variant v
+            JOIN experiment e ON e.id = v.experiment_id
+            WHERE e.status = 1 ORDER BY v.experiment_id, v.advertisement_id"#,
+            [].into_iter(),
+        );
+
+        Ok(Queries {
+            insert_stmt,
+            variant_stmt,
+            lock_stmt,
+            overlap_stmt,
+            status_stmt,
+            record_stmt,
+            select_stmt,
+            variants_stmt,
+            running_stmt,
+        })
+    }
+}
+
+impl Queries {
+    /// create a running experiment splitting traffic between `variants` of `(advertisement id, traffic)`
+    pub async fn insert(
+        &self,
+        write: &Transaction<'_>,
+        name: &str,
+        variants: &[(i32, i32)],
+    ) -> Result<Change, tokio_postgres::Error> {
+        write.execute(&self.lock_stmt, &[]).await?;
+        let id: i32 = write.query_one(&self.insert_stmt, &[&name]).await?.get(0);
+        for (advertisement_id, traffic) in variants {
+            write
+                .execute(&self.variant_stmt, &[&id, advertisement_id, traffic])
+                .await?;
+        }
+        self.overlap(write, id).await
+    }
+    pub async fn set_status(
+        &self,
+        write: &Transaction<'_>,
+        id: i32,
+        status: ExperimentStatus,
+    ) -> Result<Change, tokio_postgres::Error> {
+        write.execute(&self.lock_stmt, &[]).await?;
+        let updated = write
+            .query_opt(&self.status_stmt, &[&id, &(status as i32)])
+            .await?;
+        match (updated, status) {
+            (None, _) => Ok(Change::NotFound),
+            (Some(_), ExperimentStatus::Running) => self.overlap(write, id).await,
+            (Some(_), ExperimentStatus::Stopped) => Ok(Change::Applied(id)),
+        }
+    }
+    async fn overlap(
+        &self,
+        write: &Transaction<'_>,
+        id: i32,
+    ) -> Result<Change, tokio_postgres::Error> {
+        let overlap: bool = write.query_one(&self.overlap_stmt, &[&id]).await?.get(0);
+        Ok(if overlap {
+            Change::Overlap
+        } else {
+            Change::Applied(id)
+        })
+    }
+    pub async fn record(
+        &self,
+        write: &mut Connection<'_>,
+        metrics: &HashMap<(i32, i32), Metrics>,
+    ) -> Result<(), tokio_postgres::Error> {
+        let tx = write.transaction().await?;
+        for ((experiment_id, advertisement_id), metrics) in metrics {
+            tx.execute(
+                &self.record_stmt,
+                &[
+                    experiment_id,
+                    advertisement_id,
+                    &metrics.impressions,
+                    &metrics.clicks,
+                    &metrics.conversions,
+                    &metrics.revenue,
+                ],
+            )
+            .await?;
+        }
+        tx.commit().await
+    }
+    pub async fn select(
+        &self,
+        read: &Connection<'_>,
+        id: i32,
+    ) -> Result<Option<Experiment>, tokio_postgres::Error> {
+        let params = || [&id as &(dyn ToSql + Sync)].into_iter();
+        let Some(row) = self.select_stmt.query(read, params()).await?.pop() else {
+            return Ok(None);
+        };
+        let variants = self
+            .variants_stmt
+            .query(read, params())
+            .await?
+            .iter()
+            .map(|row| Variant {
+                advertisement_id: row.get(0),
+                traffic: row.get(1),
+                metrics: Metrics {
+                    impressions: row.get(2),
+                    clicks: row.get(3),
+                    conversions: row.get(4),
+                    revenue: row.get(5),
+                },
+            })
+            .collect();
+
+        Ok(Some(Experiment {
+            id: row.get(0),
+            name: row.get(1),
+            status: row
+                .get::<_, i32>(2)
+                .try_into()
+                .unwrap_or(ExperimentStatus::Stopped),
+            created_at: DateTime::<Local>::from(row.get::<_, SystemTime>(3)).naive_utc(),
+            stopped_at: row
+                .get::<_, Option<SystemTime>>(4)
+                .map(|x| DateTime::<Local>::from(x).naive_utc()),
+            variants,
+        }))
+    }
+    /// variants of every running experiment, as `(experiment id, advertisement id, traffic)`
+    pub async fn running(
+        &self,
+        read: &Connection<'_>,
+    ) -> Result<Vec<(i32, i32, i32)>, tokio_postgres::Error> {
+        let rows = self.running_stmt.query(read, [].into_iter()).await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect())
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[repr(i32)]
+pub enum ExperimentStatus {
+    Running = 1,
+    Stopped = 2,
+}
+
+impl TryFrom<i32> for ExperimentStatus {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(ExperimentStatus::Running),
+            2 => Ok(ExperimentStatus::Stopped),
+            x => Err(x),
+        }
+    }
+}
+
+pub enum Change {
+    Applied(i32),
+    NotFound,
+    /// a variant is already part of another running experiment
+    Overlap,
+}
+
+#[derive(Serialize)]
+pub struct Experiment {
+    pub id: i32,
+    pub name: String,
+    pub status: ExperimentStatus,
+    pub created_at: NaiveDateTime,
+    pub stopped_at: Option<NaiveDateTime>,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Serialize)]
+pub struct Variant {
+    pub advertisement_id: i32,
+    /// percentage of users assigned to this variant
+    pub traffic: i32,
+    #[serde(flatten)]
+    pub metrics: Metrics,
+}
+
+/// impressions, clicks and conversions served under an experiment
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Metrics {
+    pub impressions: i64,
+    pub clicks: i64,
+    pub conversions: i64,
+    /// sum of the conversion values, in micros
+    pub revenue: i64,
+}
+
+impl Metrics {
+    pub fn add(&mut self, other: &Metrics) {
+        self.impressions += other.impressions;
+        self.clicks += other.clicks;
+        self.conversions += other.conversions;
+        self.revenue += other.revenue;
+    }
+}
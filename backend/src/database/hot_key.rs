@@ -0,0 +1,86 @@
+use crate::database::read_write::TypedReadStatement;
+use crate::database::Connection;
+use tokio_postgres::types::{ToSql, Type};
+
+/// scores decay by a factor e every this many seconds, so that the ranking follows current traffic
+const DECAY: f64 = 3600.0;
+
+pub(crate) struct Queries {
+    record_stmt: tokio_postgres::Statement,
+    prune_stmt: tokio_postgres::Statement,
+    hottest_stmt: TypedReadStatement,
+}
+
+impl Queries {
+    pub async fn new(
+        _: &Connection<'_>,
+        write_conn: &Connection<'_>,
+    ) -> Result<Self, tokio_postgres::Error> {
+        tracing::info!("prepare hot key statement");
+        let record_stmt = write_conn
+            .prepare_typed(
+                &format!(
+                    r#"INSERT INTO read_cache_key (targeting, score) VALUES ($1, $2)
+                    ON CONFLICT (targeting) DO UPDATE
+                    SET score = read_cache_key.score
+                        * exp(extract(epoch FROM read_cache_key.seen_at - now()) / {DECAY})
+                        + EXCLUDED.score,
+                    seen_at = now();"#
+                ),
+                &[Type::JSONB, Type::FLOAT8],
+            )
+            .await?;
+        let prune_stmt = write_conn
+            .prepare_typed(
+                "DELETE FROM read_cache_key WHERE seen_at < now() - interval '1 day';",
+                &[],
+            )
+            .await?;
+        let hottest_stmt = TypedReadStatement::new(
+            format!(
+                r#"SELECT targeting FROM read_cache_key
+                ORDER BY score * exp(extract(epoch FROM seen_at - now()) / {DECAY}) DESC LIMIT $1"#
+            ),
+            [Type::INT8].into_iter(),
+        );
+
+        Ok(Queries {
+            record_stmt,
+            prune_stmt,
+            hottest_stmt,
+        })
+    }
+}
+
+impl Queries {
+    /// add the requests counted for each targeting, and forget those unseen for a day
+    pub async fn record(
+        &self,
+        write: &mut Connection<'_>,
+        hits: &[(serde_json::Value, u64)],
+    ) -> Result<(), tokio_postgres::Error> {
+        // rows are locked in the same order by every replica
+        let mut hits: Vec<_> = hits.iter().collect();
+        hits.sort_by_cached_key(|(targeting, _)| targeting.to_string());
+        let tx = write.transaction().await?;
+        for (targeting, count) in hits {
+            tx.execute(&self.record_stmt, &[targeting, &(*count as f64)])
+                .await?;
+        }
+        tx.execute(&self.prune_stmt, &[]).await?;
+        tx.commit().await
+    }
+    pub async fn hottest(
+        &self,
+        read: &Connection<'_>,
+        limit: usize,
+    ) -> Result<Vec<serde_json::Value>, tokio_postgres::Error> {
+        let limit = limit as i64;
+        let rows = self
+            .hottest_stmt
+            .query(read, [&limit as &(dyn ToSql + Sync)].into_iter())
+            .await?;
+
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+}
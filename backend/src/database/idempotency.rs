@@ -0,0 +1,133 @@
+use crate::database::Connection;
+use std::time::Duration;
+use tokio_postgres::types::Type;
+
+/// status stored while the original request is still being handled
+const IN_FLIGHT: i32 = 0;
+
+pub(crate) struct Queries {
+    claim_stmt: tokio_postgres::Statement,
+    select_stmt: tokio_postgres::Statement,
+    complete_stmt: tokio_postgres::Statement,
+    release_stmt: tokio_postgres::Statement,
+}
+
+impl Queries {
+    pub async fn new(
+        _: &Connection<'_>,
+        write_conn: &Connection<'_>,
+    ) -> Result<Self, tokio_postgres::Error> {
+        tracing::info!("prepare idempotency statement");
+        // a key older than the window is treated as free and taken over, as is an in-flight
+        // key older than its lease, whose handler crashed before completing it
+        let claim_stmt = write_conn
+            .prepare_typed(
+                r#"INSERT INTO idempotency_key (key, request_hash, status, response)
+                VALUES ($1, $2, 0, '') ON CONFLICT (key) DO UPDATE
+                SET request_hash = EXCLUDED.request_hash, status = 0, response = '', created_at = now()
+                WHERE idempotency_key.created_at < now() - make_interval(secs => $3)
+                OR (idempotency_key.status = 0 AND idempotency_key.created_at < now() - make_interval(secs => $4))
+                RETURNING key;"#,
+                &[Type::VARCHAR, Type::BYTEA, Type::FLOAT8, Type::FLOAT8],
+            )
+            .await?;
+        let select_stmt = write_conn
+            .prepare_typed(
+                "SELECT request_hash, status, response FROM idempotency_key WHERE key = $1;",
+                &[Type::VARCHAR],
+            )
+            .await?;
+        let complete_stmt = write_conn
+            .prepare_typed(
+                "UPDATE idempotency_key SET status = $2, response = $3 WHERE key = $1 AND status = 0;",
+                &[Type::VARCHAR, Type::INT4, Type::BYTEA],
+            )
+            .await?;
+        let release_stmt = write_conn
+            .prepare_typed(
+                "DELETE FROM idempotency_key WHERE key = $1 AND status = 0;",
+                &[Type::VARCHAR],
+            )
+            .await?;
+
+        Ok(Queries {
+            claim_stmt,
+            select_stmt,
+            complete_stmt,
+            release_stmt,
+        })
+    }
+}
+
+impl Queries {
+    /// `window` is how long a key is remembered, `lease` how long it stays in flight
+    pub async fn claim(
+        &self,
+        write: &Connection<'_>,
+        key: &str,
+        request_hash: &[u8],
+        window: Duration,
+        lease: Duration,
+    ) -> Result<Claim, tokio_postgres::Error> {
+        let claimed = write
+            .query_opt(
+                &self.claim_stmt,
+                &[
+                    &key,
+                    &request_hash,
+                    &window.as_secs_f64(),
+                    &lease.as_secs_f64(),
+                ],
+            )
+            .await?;
+        if claimed.is_some() {
+            return Ok(Claim::Acquired);
+        }
+
+        let Some(row) = write.query_opt(&self.select_stmt, &[&key]).await? else {
+            // released between the two statements, let the caller retry
+            return Ok(Claim::InFlight);
+        };
+        if row.get::<_, &[u8]>(0) != request_hash {
+            return Ok(Claim::Mismatch);
+        }
+        Ok(match row.get::<_, i32>(1) {
+            IN_FLIGHT => Claim::InFlight,
+            status => Claim::Replay {
+                status: status as u16,
+                response: row.get(2),
+            },
+        })
+    }
+    pub async fn complete(
+        &self,
+        write: &Connection<'_>,
+        key: &str,
+        status: u16,
+        response: &[u8],
+    ) -> Result<(), tokio_postgres::Error> {
+        write
+            .execute(&self.complete_stmt, &[&key, &(status as i32), &response])
+            .await?;
+        Ok(())
+    }
+    pub async fn release(
+        &self,
+        write: &Connection<'_>,
+        key: &str,
+    ) -> Result<(), tokio_postgres::Error> {
+        write.execute(&self.release_stmt, &[&key]).await?;
+        Ok(())
+    }
+}
+
+pub enum Claim {
+    /// first request with this key, proceed and [`Queries::complete`] it
+    Acquired,
+    /// the key was used by an identical request, return its response
+    Replay { status: u16, response: Vec<u8> },
+    /// the key was used by a different request
+    Mismatch,
+    /// the original request has not finished yet, and its lease has not run out
+    InFlight,
+}
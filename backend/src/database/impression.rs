@@ -0,0 +1,111 @@
+use crate::database::event::Event;
+use chrono::NaiveDateTime;
+use common::{Country, Gender, Platform};
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+use tokio_postgres::types::{ToSql, Type};
+
+/// an advertisement served to one request, as sealed into its tracking tokens
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Impression {
+    pub advertisement_id: i32,
+    /// random per `GET /ad` request
+    pub request_id: String,
+    pub experiment_id: Option<i32>,
+    /// `user_id` of the request, conversions are attributed to the clicks of a user
+    pub user_id: Option<String>,
+    /// charged for the impression in micros, 0 for a CPC bid
+    pub cost: i64,
+    /// charged for a click in micros, 0 for a CPM bid
+    #[serde(default)]
+    pub click_cost: i64,
+    /// unpaid fallback, never charged
+    pub house: bool,
+    pub age: Option<i32>,
+    #[serde(with = "common::alpha2")]
+    pub country: Option<Country>,
+    pub platform: Option<Platform>,
+    pub gender: Option<Gender>,
+    pub placement: Option<String>,
+    pub served_at: NaiveDateTime,
+    /// set when tracked as invalid traffic, never sealed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invalid: Option<Invalid>,
+}
+
+impl Impression {
+    /// country, platform and gender as stored
+    pub(crate) fn targeting_ids(&self) -> (Option<i32>, Option<i32>, Option<i32>) {
+        (
+            self.country.clone().map(|x| x.into_id() as i32),
+            self.platform.map(|x| x as i32),
+            self.gender.clone().map(|x| x as i32),
+        )
+    }
+}
+
+/// recorded once per request and advertisement, see the unique key of `impression`
+impl Event for Impression {
+    const TABLE: &'static str = "impression";
+    const KEY: &'static [&'static str] = &["request_id", "advertisement_id"];
+    const COLUMNS: &'static [(&'static str, Type)] = &[
+        ("advertisement_id", Type::INT4),
+        ("request_id", Type::VARCHAR),
+        ("experiment_id", Type::INT4),
+        ("cost", Type::INT8),
+        ("house", Type::BOOL),
+        ("age", Type::INT4),
+        ("country", Type::INT4),
+        ("platform", Type::INT4),
+        ("gender", Type::INT4),
+        ("placement", Type::VARCHAR),
+        ("served_at", Type::TIMESTAMP),
+        ("invalid", Type::INT4),
+    ];
+    fn values(&self) -> Vec<Box<dyn ToSql + Sync + Send>> {
+        let (country, platform, gender) = self.targeting_ids();
+        vec![
+            Box::new(self.advertisement_id),
+            Box::new(self.request_id.clone()),
+            Box::new(self.experiment_id),
+            Box::new(self.cost),
+            Box::new(self.house),
+            Box::new(self.age),
+            Box::new(country),
+            Box::new(platform),
+            Box::new(gender),
+            Box::new(self.placement.clone()),
+            Box::new(SystemTime::from(self.served_at.and_utc())),
+            Box::new(self.invalid.map(|x| x as i32)),
+        ]
+    }
+}
+
+/// why a tracked event is stored but neither billed nor reported, see [`crate::traffic`]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[repr(i32)]
+pub enum Invalid {
+    /// the user agent matches the bot list
+    BotUserAgent = 1,
+    /// more clicks from the IP address or user than the limit allows
+    ClickRate = 2,
+    /// a click on an advertisement whose impression was never tracked
+    NoImpression = 3,
+    /// a click sooner after serving than a person could click
+    TooFast = 4,
+}
+
+impl TryFrom<i32> for Invalid {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Invalid::BotUserAgent),
+            2 => Ok(Invalid::ClickRate),
+            3 => Ok(Invalid::NoImpression),
+            4 => Ok(Invalid::TooFast),
+            x => Err(x),
+        }
+    }
+}
@@ -1,13 +1,40 @@
-use crate::database::read_write::Config;
+use crate::database::read_write::{Config, Notifications};
 use bb8::PooledConnection;
 use bb8_postgres::PostgresConnectionManager;
+use chrono::NaiveDateTime;
+use common::{Country, Platform};
+use std::collections::HashMap;
 use std::env;
+use std::time::Duration;
 use tokio_postgres::NoTls;
 
 pub mod advertisement;
+pub mod click;
+pub mod conversion;
+pub mod delivery;
+pub mod event;
+pub mod experiment;
+pub mod hot_key;
+pub mod idempotency;
+pub mod impression;
 pub mod read_write;
+pub mod report;
+pub mod revision;
+pub mod traffic;
 
-pub use advertisement::{Advertisement, Condition, PartialAdvertisement};
+pub use advertisement::{
+    Advertisement, After, BidType, Budget, Condition, Insert, PartialAdvertisement, Sort, Status,
+    Targeted,
+};
+pub use click::Click;
+pub use conversion::{Attribution, Conversion, Postback};
+pub use delivery::Usage;
+pub use event::Event;
+pub use experiment::{Change, Experiment, ExperimentStatus, Metrics};
+pub use idempotency::Claim;
+pub use impression::{Impression, Invalid};
+pub use report::{Dimension, Filter, ReportRow};
+pub use revision::{Action, Revision};
 
 type Connection<'a> = PooledConnection<'a, Manager>;
 
@@ -16,6 +43,14 @@ type Manager = PostgresConnectionManager<NoTls>;
 pub struct Client {
     inner_client: read_write::Client,
     queries: advertisement::Queries,
+    revisions: revision::Queries,
+    idempotency: idempotency::Queries,
+    delivery: delivery::Queries,
+    experiments: experiment::Queries,
+    hot_keys: hot_key::Queries,
+    conversions: conversion::Queries,
+    reports: report::Queries,
+    traffic: traffic::Queries,
 }
 
 impl Client {
@@ -38,24 +73,371 @@ impl Client {
             advertisement::Queries::new(&inner_client.read().await, &inner_client.write().await)
                 .await
                 .unwrap();
+        let revisions =
+            revision::Queries::new(&inner_client.read().await, &inner_client.write().await)
+                .await
+                .unwrap();
+        let idempotency =
+            idempotency::Queries::new(&inner_client.read().await, &inner_client.write().await)
+                .await
+                .unwrap();
+
+        let delivery =
+            delivery::Queries::new(&inner_client.read().await, &inner_client.write().await)
+                .await
+                .unwrap();
+        let experiments =
+            experiment::Queries::new(&inner_client.read().await, &inner_client.write().await)
+                .await
+                .unwrap();
+        let hot_keys =
+            hot_key::Queries::new(&inner_client.read().await, &inner_client.write().await)
+                .await
+                .unwrap();
+        let conversions =
+            conversion::Queries::new(&inner_client.read().await, &inner_client.write().await)
+                .await
+                .unwrap();
+        let reports = report::Queries::new(&inner_client.read().await, &inner_client.write().await)
+            .await
+            .unwrap();
+        let traffic =
+            traffic::Queries::new(&inner_client.read().await, &inner_client.write().await)
+                .await
+                .unwrap();
 
         Self {
             inner_client,
             queries,
+            revisions,
+            idempotency,
+            delivery,
+            experiments,
+            hot_keys,
+            conversions,
+            reports,
+            traffic,
         }
     }
-    pub async fn insert(&self, advertisement: &Advertisement) -> Result<(), tokio_postgres::Error> {
-        self.queries
-            .insert(advertisement, &self.inner_client.write().await)
+    pub async fn insert(
+        &self,
+        advertisement: &Advertisement,
+        actor: &str,
+    ) -> Result<Insert, tokio_postgres::Error> {
+        let mut conn = self.inner_client.write().await;
+        let tx = conn.transaction().await?;
+        let insert = self.queries.insert(advertisement, &tx).await?;
+        if let Insert::Inserted(id) = insert {
+            self.revisions
+                .insert(&tx, id, actor, Action::Create, None, advertisement)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(insert)
+    }
+    /// replace the targeting and schedule of an advertisement, keeping its status
+    ///
+    /// return `None` if the advertisement does not exist
+    pub async fn update(
+        &self,
+        id: i32,
+        advertisement: Advertisement,
+        actor: &str,
+    ) -> Result<Option<()>, tokio_postgres::Error> {
+        self.modify(id, actor, Action::Update, |before| Advertisement {
+            status: before.status,
+            ..advertisement
+        })
+        .await
+    }
+    pub async fn set_status(
+        &self,
+        id: i32,
+        status: Status,
+        actor: &str,
+    ) -> Result<Option<()>, tokio_postgres::Error> {
+        self.modify(id, actor, Action::Status, |before| Advertisement {
+            status,
+            ..before.clone()
+        })
+        .await
+    }
+    /// restore the advertisement to the state recorded by `revision`
+    ///
+    /// return `None` if either the advertisement or the revision does not exist
+    pub async fn rollback(
+        &self,
+        id: i32,
+        revision: i32,
+        actor: &str,
+    ) -> Result<Option<()>, tokio_postgres::Error> {
+        let mut conn = self.inner_client.write().await;
+        let tx = conn.transaction().await?;
+        let Some(before) = self.queries.select_for_update(id, &tx).await? else {
+            return Ok(None);
+        };
+        let Some(after) = self.revisions.snapshot(&tx, id, revision).await? else {
+            return Ok(None);
+        };
+        self.queries.update(id, &after, &tx).await?;
+        self.revisions
+            .insert(&tx, id, actor, Action::Rollback, Some(&before), &after)
+            .await?;
+        tx.commit().await?;
+        Ok(Some(()))
+    }
+    async fn modify(
+        &self,
+        id: i32,
+        actor: &str,
+        action: Action,
+        f: impl FnOnce(&Advertisement) -> Advertisement,
+    ) -> Result<Option<()>, tokio_postgres::Error> {
+        let mut conn = self.inner_client.write().await;
+        let tx = conn.transaction().await?;
+        let Some(before) = self.queries.select_for_update(id, &tx).await? else {
+            return Ok(None);
+        };
+        let after = f(&before);
+        self.queries.update(id, &after, &tx).await?;
+        self.revisions
+            .insert(&tx, id, actor, action, Some(&before), &after)
+            .await?;
+        tx.commit().await?;
+        Ok(Some(()))
+    }
+    pub async fn history(&self, id: i32) -> Result<Vec<Revision>, tokio_postgres::Error> {
+        self.revisions
+            .history(&self.inner_client.read().await, id)
             .await
     }
     pub async fn query_partial(
         &self,
         cond: Condition,
+        sort: Sort,
+        after: Option<After>,
         (limit, offset): (usize, usize),
     ) -> Result<Vec<PartialAdvertisement>, tokio_postgres::Error> {
         self.queries
-            .query_partial(&self.inner_client.read().await, cond, (limit, offset))
+            .query_partial(
+                &self.inner_client.read().await,
+                cond,
+                sort,
+                after,
+                (limit, offset),
+            )
+            .await
+    }
+    pub async fn query_house(
+        &self,
+        country: Option<Country>,
+        platform: Option<Platform>,
+        limit: usize,
+    ) -> Result<Vec<PartialAdvertisement>, tokio_postgres::Error> {
+        self.queries
+            .query_house(&self.inner_client.read().await, country, platform, limit)
+            .await
+    }
+    pub async fn changed_since(
+        &self,
+        since: NaiveDateTime,
+    ) -> Result<Vec<Targeted>, tokio_postgres::Error> {
+        self.queries
+            .changed_since(&self.inner_client.read().await, since)
+            .await
+    }
+    /// read from the write host, so that a change just notified is visible
+    pub async fn targeted(&self, ids: &[i32]) -> Result<Vec<Targeted>, tokio_postgres::Error> {
+        self.queries
+            .targeted(&self.inner_client.write().await, ids)
+            .await
+    }
+    /// read from the write host, so that it covers every committed change
+    pub async fn advertisement_version(&self) -> Result<i64, tokio_postgres::Error> {
+        self.queries.version(&self.inner_client.write().await).await
+    }
+    pub async fn landing_url(&self, id: i32) -> Result<Option<String>, tokio_postgres::Error> {
+        self.queries
+            .landing_url(&self.inner_client.read().await, id)
+            .await
+    }
+    /// dedicated connection receiving the payloads notified on `channel`
+    pub async fn listen(&self, channel: &str) -> Result<Notifications, tokio_postgres::Error> {
+        self.inner_client.listen(channel).await
+    }
+    pub async fn claim_idempotency_key(
+        &self,
+        key: &str,
+        request_hash: &[u8],
+        window: Duration,
+        lease: Duration,
+    ) -> Result<Claim, tokio_postgres::Error> {
+        self.idempotency
+            .claim(
+                &self.inner_client.write().await,
+                key,
+                request_hash,
+                window,
+                lease,
+            )
+            .await
+    }
+    pub async fn complete_idempotency_key(
+        &self,
+        key: &str,
+        status: u16,
+        response: &[u8],
+    ) -> Result<(), tokio_postgres::Error> {
+        self.idempotency
+            .complete(&self.inner_client.write().await, key, status, response)
+            .await
+    }
+    pub async fn release_idempotency_key(&self, key: &str) -> Result<(), tokio_postgres::Error> {
+        self.idempotency
+            .release(&self.inner_client.write().await, key)
+            .await
+    }
+    /// add delivered impressions and spend to today's counters
+    pub async fn record_delivery(
+        &self,
+        usage: &HashMap<i32, Usage>,
+    ) -> Result<(), tokio_postgres::Error> {
+        self.delivery
+            .record(&mut self.inner_client.write().await, usage)
+            .await
+    }
+    /// usage of the advertisements delivered since `since`, with the latest change read
+    pub async fn delivery_usage(
+        &self,
+        since: NaiveDateTime,
+    ) -> Result<(HashMap<i32, Usage>, NaiveDateTime), tokio_postgres::Error> {
+        self.delivery
+            .usage(&self.inner_client.write().await, since)
+            .await
+    }
+    /// start an experiment over `variants` of `(advertisement id, traffic)`
+    pub async fn create_experiment(
+        &self,
+        name: &str,
+        variants: &[(i32, i32)],
+    ) -> Result<Change, tokio_postgres::Error> {
+        let mut conn = self.inner_client.write().await;
+        let tx = conn.transaction().await?;
+        let change = self.experiments.insert(&tx, name, variants).await?;
+        if let Change::Applied(_) = change {
+            tx.commit().await?;
+        }
+        Ok(change)
+    }
+    pub async fn set_experiment_status(
+        &self,
+        id: i32,
+        status: ExperimentStatus,
+    ) -> Result<Change, tokio_postgres::Error> {
+        let mut conn = self.inner_client.write().await;
+        let tx = conn.transaction().await?;
+        let change = self.experiments.set_status(&tx, id, status).await?;
+        if let Change::Applied(_) = change {
+            tx.commit().await?;
+        }
+        Ok(change)
+    }
+    pub async fn experiment(&self, id: i32) -> Result<Option<Experiment>, tokio_postgres::Error> {
+        self.experiments
+            .select(&self.inner_client.read().await, id)
+            .await
+    }
+    pub async fn running_experiments(&self) -> Result<Vec<(i32, i32, i32)>, tokio_postgres::Error> {
+        self.experiments
+            .running(&self.inner_client.read().await)
+            .await
+    }
+    /// add impressions and clicks to the metrics of `(experiment id, advertisement id)`
+    pub async fn record_experiments(
+        &self,
+        metrics: &HashMap<(i32, i32), Metrics>,
+    ) -> Result<(), tokio_postgres::Error> {
+        self.experiments
+            .record(&mut self.inner_client.write().await, metrics)
+            .await
+    }
+    /// add request counts to the targetings of `GET /ad`, as JSON
+    pub async fn record_hot_keys(
+        &self,
+        hits: &[(serde_json::Value, u64)],
+    ) -> Result<(), tokio_postgres::Error> {
+        self.hot_keys
+            .record(&mut self.inner_client.write().await, hits)
+            .await
+    }
+    /// the `limit` most requested targetings lately, as JSON
+    pub async fn hot_keys(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<serde_json::Value>, tokio_postgres::Error> {
+        self.hot_keys
+            .hottest(&self.inner_client.read().await, limit)
+            .await
+    }
+    /// append the events in one transaction, skipping those already recorded, and return
+    /// the positions of those appended
+    pub async fn copy_events<E: Event>(
+        &self,
+        events: &[E],
+    ) -> Result<Vec<usize>, bb8::RunError<tokio_postgres::Error>> {
+        let mut conn = self.inner_client.try_write().await?;
+        Ok(event::copy(&mut conn, events).await?)
+    }
+    /// attribute a conversion to a click within `lookback`
+    pub async fn record_conversion(
+        &self,
+        postback: &Postback,
+        lookback: Duration,
+    ) -> Result<Attribution, tokio_postgres::Error> {
+        self.conversions
+            .attribute(&self.inner_client.write().await, postback, lookback)
+            .await
+    }
+    /// recount the latest hours of tracked events, false when another backend is at it
+    pub async fn rollup_reports(&self) -> Result<bool, tokio_postgres::Error> {
+        self.reports
+            .rollup(&mut self.inner_client.write().await)
+            .await
+    }
+    pub async fn report(
+        &self,
+        dimensions: &[Dimension],
+        filter: &Filter,
+    ) -> Result<Vec<ReportRow>, tokio_postgres::Error> {
+        self.reports
+            .select(&self.inner_client.read().await, dimensions, filter)
+            .await
+    }
+    /// case-insensitive substrings of bot user agents
+    pub async fn bot_user_agents(&self) -> Result<Vec<String>, tokio_postgres::Error> {
+        self.traffic.bots(&self.inner_client.read().await).await
+    }
+    pub async fn set_bot_user_agents(
+        &self,
+        patterns: &[String],
+    ) -> Result<(), tokio_postgres::Error> {
+        let mut conn = self.inner_client.write().await;
+        let tx = conn.transaction().await?;
+        self.traffic.replace_bots(&tx, patterns).await?;
+        tx.commit().await
+    }
+    /// whether the impression of the served advertisement was recorded
+    pub async fn impressed(
+        &self,
+        request_id: &str,
+        advertisement_id: i32,
+    ) -> Result<bool, tokio_postgres::Error> {
+        self.traffic
+            .impressed(
+                &self.inner_client.write().await,
+                request_id,
+                advertisement_id,
+            )
             .await
     }
 }
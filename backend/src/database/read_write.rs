@@ -1,8 +1,9 @@
 use crate::database::{Connection, Manager};
 use bb8::Pool;
 use bb8_postgres::PostgresConnectionManager;
+use tokio::sync::mpsc;
 use tokio_postgres::types::{ToSql, Type};
-use tokio_postgres::{NoTls, Row};
+use tokio_postgres::{AsyncMessage, NoTls, Row};
 
 static POOL_EXHAUSTED_MSG: &str = "cannot found/add new connection to pool";
 
@@ -46,6 +47,8 @@ impl Config {
 pub struct Client {
     read_pool: Pool<Manager>,
     write_pool: Pool<Manager>,
+    /// for connections outside of the pools
+    write_config: String,
 }
 
 impl Client {
@@ -61,7 +64,9 @@ impl Client {
             PostgresConnectionManager::new_from_stringlike(write.to_stringlike(), NoTls)?;
         let write_pool = Pool::builder().max_size(15).build(write_manager).await?;
 
-        Client::new(read_pool, write_pool).await
+        let mut client = Client::new(read_pool, write_pool).await?;
+        client.write_config = write.to_stringlike();
+        Ok(client)
     }
     pub async fn new(
         read: Pool<PostgresConnectionManager<NoTls>>,
@@ -70,16 +75,69 @@ impl Client {
         Ok(Client {
             read_pool: read,
             write_pool: write,
+            write_config: Config::default().to_stringlike(),
         })
     }
-    pub async fn read(&self) -> Connection {
+    pub async fn read(&self) -> Connection<'_> {
         tracing::info!(counter.database.read = 1);
         self.read_pool.get().await.expect(POOL_EXHAUSTED_MSG)
     }
-    pub async fn write(&self) -> Connection {
+    pub async fn write(&self) -> Connection<'_> {
         tracing::info!(counter.database.write = 1);
         self.write_pool.get().await.expect(POOL_EXHAUSTED_MSG)
     }
+    /// like [`Client::write`], failing instead when the write host is unreachable
+    pub async fn try_write(&self) -> Result<Connection<'_>, bb8::RunError<tokio_postgres::Error>> {
+        tracing::info!(counter.database.write = 1);
+        self.write_pool.get().await
+    }
+    /// `LISTEN` on a connection of its own to the write host, where notifications are sent
+    pub async fn listen(&self, channel: &str) -> Result<Notifications, tokio_postgres::Error> {
+        let (client, mut connection) = tokio_postgres::connect(&self.write_config, NoTls).await?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                match std::future::poll_fn(|cx| connection.poll_message(cx)).await {
+                    Some(Ok(AsyncMessage::Notification(x))) => {
+                        if sender.send(x.payload().to_string()).is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => {
+                        tracing::warn!("listen connection failed: {:?}", err);
+                        return;
+                    }
+                    None => return,
+                }
+            }
+        });
+        client.batch_execute(&format!("LISTEN {}", channel)).await?;
+        Ok(Notifications {
+            _client: client,
+            receiver,
+        })
+    }
+}
+
+/// payloads notified on a channel, until the connection is lost
+pub struct Notifications {
+    /// the connection closes when dropped
+    _client: tokio_postgres::Client,
+    receiver: mpsc::UnboundedReceiver<String>,
+}
+
+impl Notifications {
+    /// wait for a payload, then take every payload already received
+    ///
+    /// return `None` once the connection is lost
+    pub async fn recv_batch(&mut self) -> Option<Vec<String>> {
+        let mut batch = vec![self.receiver.recv().await?];
+        while let Ok(x) = self.receiver.try_recv() {
+            batch.push(x);
+        }
+        Some(batch)
+    }
 }
 
 pub struct TypedReadStatement {
@@ -101,7 +159,7 @@ impl TypedReadStatement {
     ) -> Result<Vec<Row>, tokio_postgres::Error> {
         let param = param
             .map(|x| x as &(dyn ToSql + Sync))
-            .zip(self.types.clone().into_iter())
+            .zip(self.types.clone())
             .collect::<Vec<_>>();
         conn.query_typed(self.raw.as_ref(), &param).await
     }
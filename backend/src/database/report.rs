@@ -0,0 +1,209 @@
+use crate::database::read_write::TypedReadStatement;
+use crate::database::Connection;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime};
+use common::{Country, Gender, Platform};
+use serde::Deserialize;
+use std::time::SystemTime;
+use tokio_postgres::types::{ToSql, Type};
+
+pub(crate) struct Queries {
+    lock_stmt: tokio_postgres::Statement,
+    rollup_stmt: tokio_postgres::Statement,
+}
+
+impl Queries {
+    pub async fn new(
+        _: &Connection<'_>,
+        write_conn: &Connection<'_>,
+    ) -> Result<Self, tokio_postgres::Error> {
+        tracing::info!("prepare report statement");
+        // one backend rolls up at a time, the others skip the round
+        let lock_stmt = write_conn
+            .prepare_typed(
+                "SELECT pg_try_advisory_xact_lock(hashtext('report_hour'));",
+                &[],
+            )
+            .await?;
+        // recount every hour since the last one rolled up, including it: events of an hour
+        // keep arriving until its transactions commit, and a recount replaces the totals;
+        // invalid traffic and conversions of invalid clicks are left out
+        let rollup_stmt = write_conn
+            .prepare_typed(
+                r#"WITH since AS (
+                    SELECT coalesce(max(hour) - interval '1 hour', '-infinity') AS at FROM report_hour
+                ), events AS (
+                    SELECT date_trunc('hour', created_at) AS hour, advertisement_id, country, platform, gender,
+                    1 AS impressions, 0 AS clicks, 0 AS conversions,
+                    CASE WHEN house THEN 0 ELSE cost END AS spend, 0 AS revenue
+                    FROM impression, since WHERE created_at >= since.at AND invalid IS NULL
+                    UNION ALL
+                    SELECT date_trunc('hour', created_at), advertisement_id, country, platform, gender,
+                    0, 1, 0, cost, 0
+                    FROM click, since WHERE created_at >= since.at AND invalid IS NULL
+                    UNION ALL
+                    SELECT date_trunc('hour', conversion.created_at), conversion.advertisement_id,
+                    click.country, click.platform, click.gender, 0, 0, 1, 0, conversion.value
+                    FROM conversion JOIN click ON click.id = conversion.click_id, since
+                    WHERE conversion.created_at >= since.at AND click.invalid IS NULL
+                )
+                INSERT INTO report_hour (hour, advertisement_id, country, platform, gender, impressions,
+                clicks, conversions, spend, revenue)
+                SELECT hour, advertisement_id, country, platform, gender, sum(impressions), sum(clicks),
+                sum(conversions), sum(spend), sum(revenue)
+                FROM events GROUP BY hour, advertisement_id, country, platform, gender
+                ON CONFLICT (hour, advertisement_id, country, platform, gender) DO UPDATE
+                SET impressions = EXCLUDED.impressions, clicks = EXCLUDED.clicks,
+                conversions = EXCLUDED.conversions, spend = EXCLUDED.spend, revenue = EXCLUDED.revenue;"#,
+                &[],
+            )
+            .await?;
+
+        Ok(Queries {
+            lock_stmt,
+            rollup_stmt,
+        })
+    }
+}
+
+impl Queries {
+    /// recount the latest hours into `report_hour`, false when another backend is rolling up
+    pub async fn rollup(&self, write: &mut Connection<'_>) -> Result<bool, tokio_postgres::Error> {
+        let tx = write.transaction().await?;
+        let locked: bool = tx.query_one(&self.lock_stmt, &[]).await?.get(0);
+        if !locked {
+            return Ok(false);
+        }
+        tx.execute(&self.rollup_stmt, &[]).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+    /// totals of the hours within the filter, grouped by `dimensions` in order
+    pub async fn select(
+        &self,
+        read: &Connection<'_>,
+        dimensions: &[Dimension],
+        filter: &Filter,
+    ) -> Result<Vec<ReportRow>, tokio_postgres::Error> {
+        let columns: Vec<_> = dimensions.iter().map(|x| x.column()).collect();
+        let positions: Vec<_> = (1..=columns.len()).map(|x| x.to_string()).collect();
+        let (select, group) = match columns.is_empty() {
+            true => (String::new(), String::new()),
+            false => (
+                format!("{}, ", columns.join(", ")),
+                format!("GROUP BY {0} ORDER BY {0}", positions.join(", ")),
+            ),
+        };
+        let stmt = TypedReadStatement::new(
+            format!(
+                r#"SELECT {select}coalesce(sum(impressions), 0)::int8, coalesce(sum(clicks), 0)::int8,
+                coalesce(sum(conversions), 0)::int8, coalesce(sum(spend), 0)::int8,
+                coalesce(sum(revenue), 0)::int8
+                FROM report_hour JOIN advertisement ON advertisement.id = report_hour.advertisement_id
+                WHERE hour >= $1 AND hour < $2
+                AND ($3::int4 IS NULL OR report_hour.advertisement_id = $3)
+                AND ($4::varchar IS NULL OR advertisement.campaign = $4)
+                {group}"#
+            ),
+            [Type::TIMESTAMP, Type::TIMESTAMP, Type::INT4, Type::VARCHAR].into_iter(),
+        );
+        let from = SystemTime::from(filter.from.and_utc());
+        let to = SystemTime::from(filter.to.and_utc());
+        let params: [&(dyn ToSql + Sync); 4] =
+            [&from, &to, &filter.advertisement_id, &filter.campaign];
+        let rows = stmt.query(read, params.into_iter()).await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let mut report = ReportRow::default();
+                for (i, dimension) in dimensions.iter().enumerate() {
+                    match dimension {
+                        Dimension::Ad => report.advertisement_id = row.get(i),
+                        Dimension::Campaign => report.campaign = row.get(i),
+                        Dimension::Day => report.day = Some(timestamp(row.get(i)).date()),
+                        Dimension::Hour => report.hour = Some(timestamp(row.get(i))),
+                        Dimension::Country => {
+                            report.country = row
+                                .get::<_, Option<i32>>(i)
+                                .and_then(|x| Country::from_id(x as u32))
+                        }
+                        Dimension::Platform => {
+                            report.platform =
+                                row.get::<_, Option<i32>>(i).and_then(|x| x.try_into().ok())
+                        }
+                        Dimension::Gender => {
+                            report.gender =
+                                row.get::<_, Option<i32>>(i).and_then(|x| x.try_into().ok())
+                        }
+                    }
+                }
+                let n = dimensions.len();
+                report.impressions = row.get(n);
+                report.clicks = row.get(n + 1);
+                report.conversions = row.get(n + 2);
+                report.spend = row.get(n + 3);
+                report.revenue = row.get(n + 4);
+                report
+            })
+            .collect())
+    }
+}
+
+fn timestamp(value: SystemTime) -> NaiveDateTime {
+    DateTime::<Local>::from(value).naive_utc()
+}
+
+/// what a report is grouped by
+#[derive(Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Dimension {
+    Ad,
+    Campaign,
+    Day,
+    Hour,
+    Country,
+    Platform,
+    Gender,
+}
+
+impl Dimension {
+    fn column(self) -> &'static str {
+        match self {
+            Dimension::Ad => "report_hour.advertisement_id",
+            Dimension::Campaign => "advertisement.campaign",
+            Dimension::Day => "date_trunc('day', report_hour.hour)",
+            Dimension::Hour => "report_hour.hour",
+            Dimension::Country => "report_hour.country",
+            Dimension::Platform => "report_hour.platform",
+            Dimension::Gender => "report_hour.gender",
+        }
+    }
+}
+
+/// hours from `from` until `to`, optionally of one advertisement or campaign
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pub from: NaiveDateTime,
+    pub to: NaiveDateTime,
+    pub advertisement_id: Option<i32>,
+    pub campaign: Option<String>,
+}
+
+/// totals of one group, a dimension not grouped by is `None`, as is an untargeted one
+#[derive(Debug, Default, Clone)]
+pub struct ReportRow {
+    pub advertisement_id: Option<i32>,
+    pub campaign: Option<String>,
+    pub day: Option<NaiveDate>,
+    pub hour: Option<NaiveDateTime>,
+    pub country: Option<Country>,
+    pub platform: Option<Platform>,
+    pub gender: Option<Gender>,
+    pub impressions: i64,
+    pub clicks: i64,
+    pub conversions: i64,
+    /// of impressions of CPM bids and clicks of CPC bids, in micros, house impressions are free
+    pub spend: i64,
+    /// reported with conversions, in micros
+    pub revenue: i64,
+}
@@ -0,0 +1,215 @@
+use crate::database::advertisement::Advertisement;
+use crate::database::read_write::TypedReadStatement;
+use crate::database::Connection;
+use chrono::{DateTime, Local, NaiveDateTime};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::error::Error;
+use std::time::SystemTime;
+use tokio_postgres::types::{FromSql, Json, ToSql, Type};
+use tokio_postgres::Transaction;
+
+pub(crate) struct Queries {
+    insert_stmt: tokio_postgres::Statement,
+    snapshot_stmt: tokio_postgres::Statement,
+    history_stmt: TypedReadStatement,
+}
+
+impl Queries {
+    pub async fn new(
+        _: &Connection<'_>,
+        write_conn: &Connection<'_>,
+    ) -> Result<Self, tokio_postgres::Error> {
+        tracing::info!("prepare revision statement");
+        let insert_stmt = write_conn
+            .prepare_typed(
+                r#"INSERT INTO advertisement_revision (advertisement_id, actor, action, diff, snapshot)
+                VALUES ($1, $2, $3, $4, $5);"#,
+                &[
+                    Type::INT4,
+                    Type::VARCHAR,
+                    Type::INT4,
+                    Type::JSONB,
+                    Type::JSONB,
+                ],
+            )
+            .await?;
+        let snapshot_stmt = write_conn
+            .prepare_typed(
+                "SELECT snapshot FROM advertisement_revision WHERE advertisement_id = $1 AND id = $2;",
+                &[Type::INT4, Type::INT4],
+            )
+            .await?;
+        let history_stmt = TypedReadStatement::new(
+            r#"SELECT id, actor, action, created_at, diff FROM advertisement_revision
+            WHERE advertisement_id = $1 ORDER BY id"#,
+            [Type::INT4].into_iter(),
+        );
+
+        Ok(Queries {
+            insert_stmt,
+            snapshot_stmt,
+            history_stmt,
+        })
+    }
+}
+
+impl Queries {
+    /// record `after` as the new state of the advertisement, with a diff against `before`
+    pub async fn insert(
+        &self,
+        write: &Transaction<'_>,
+        advertisement_id: i32,
+        actor: &str,
+        action: Action,
+        before: Option<&Advertisement>,
+        after: &Advertisement,
+    ) -> Result<(), tokio_postgres::Error> {
+        let before = before.map(|x| serde_json::to_value(x).unwrap());
+        let snapshot = serde_json::to_value(after).unwrap();
+        write
+            .execute(
+                &self.insert_stmt,
+                &[
+                    &advertisement_id,
+                    &actor,
+                    &(action as i32),
+                    &diff(before.as_ref(), &snapshot),
+                    &snapshot,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+    /// the advertisement as of `revision`, a snapshot that no longer deserializes is an
+    /// error rather than a missing revision
+    pub async fn snapshot(
+        &self,
+        write: &Transaction<'_>,
+        advertisement_id: i32,
+        revision: i32,
+    ) -> Result<Option<Advertisement>, tokio_postgres::Error> {
+        let row = write
+            .query_opt(&self.snapshot_stmt, &[&advertisement_id, &revision])
+            .await?;
+        row.map(|row| row.try_get::<_, Json<Advertisement>>(0).map(|x| x.0))
+            .transpose()
+    }
+    pub async fn history(
+        &self,
+        read: &Connection<'_>,
+        advertisement_id: i32,
+    ) -> Result<Vec<Revision>, tokio_postgres::Error> {
+        let rows = self
+            .history_stmt
+            .query(read, [&advertisement_id as &(dyn ToSql + Sync)].into_iter())
+            .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(Revision {
+                    id: row.get(0),
+                    actor: row.get(1),
+                    action: row.try_get(2)?,
+                    created_at: DateTime::<Local>::from(row.get::<_, SystemTime>(3)).naive_utc(),
+                    diff: row.get(4),
+                })
+            })
+            .collect()
+    }
+}
+
+/// field-level diff between two snapshots, as `{field: {from, to}}`
+fn diff(before: Option<&Value>, after: &Value) -> Value {
+    let empty = Map::new();
+    let before = before.and_then(Value::as_object).unwrap_or(&empty);
+    let mut changes = Map::new();
+    if let Some(after) = after.as_object() {
+        for (field, to) in after {
+            let from = before.get(field).unwrap_or(&Value::Null);
+            if from != to {
+                changes.insert(field.clone(), json!({ "from": from, "to": to }));
+            }
+        }
+    }
+    Value::Object(changes)
+}
+
+#[derive(Serialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[repr(i32)]
+pub enum Action {
+    Create = 1,
+    Update = 2,
+    Status = 3,
+    Rollback = 4,
+}
+
+impl TryFrom<i32> for Action {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Action::Create),
+            2 => Ok(Action::Update),
+            3 => Ok(Action::Status),
+            4 => Ok(Action::Rollback),
+            x => Err(x),
+        }
+    }
+}
+
+/// an unknown action code fails the query instead of being read as some other action
+impl<'a> FromSql<'a> for Action {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        i32::from_sql(ty, raw)?
+            .try_into()
+            .map_err(|x| format!("unknown revision action {x}").into())
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <i32 as FromSql>::accepts(ty)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Revision {
+    pub id: i32,
+    pub actor: String,
+    pub action: Action,
+    pub created_at: NaiveDateTime,
+    pub diff: Value,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_round_trips_country() {
+        let snapshot = json!({
+            "title": "spring sale",
+            "age_range": [20, 40],
+            "country": "kor",
+            "platform": null,
+            "gender": null,
+            "end_at": "2030-01-01T00:00:00",
+            "status": "active",
+            "advertiser": null,
+        });
+        let advertisement: Advertisement = serde_json::from_value(snapshot).unwrap();
+        let stored = serde_json::to_value(&advertisement).unwrap();
+        assert_eq!(stored["country"], "KR");
+        let restored: Advertisement = serde_json::from_value(stored).unwrap();
+        assert_eq!(restored, advertisement);
+    }
+
+    #[test]
+    fn unknown_action_is_rejected() {
+        let raw = 3i32.to_be_bytes();
+        assert_eq!(Action::from_sql(&Type::INT4, &raw).unwrap(), Action::Status);
+        let raw = 9i32.to_be_bytes();
+        let err = Action::from_sql(&Type::INT4, &raw).unwrap_err();
+        assert_eq!(err.to_string(), "unknown revision action 9");
+    }
+}
@@ -0,0 +1,77 @@
+use crate::database::read_write::TypedReadStatement;
+use crate::database::Connection;
+use tokio_postgres::types::Type;
+use tokio_postgres::Transaction;
+
+pub(crate) struct Queries {
+    delete_bots_stmt: tokio_postgres::Statement,
+    insert_bot_stmt: tokio_postgres::Statement,
+    impressed_stmt: tokio_postgres::Statement,
+    bots_stmt: TypedReadStatement,
+}
+
+impl Queries {
+    pub async fn new(
+        _: &Connection<'_>,
+        write_conn: &Connection<'_>,
+    ) -> Result<Self, tokio_postgres::Error> {
+        tracing::info!("prepare traffic statement");
+        let delete_bots_stmt = write_conn
+            .prepare_typed("DELETE FROM bot_user_agent;", &[])
+            .await?;
+        let insert_bot_stmt = write_conn
+            .prepare_typed(
+                "INSERT INTO bot_user_agent (pattern) VALUES ($1) ON CONFLICT DO NOTHING;",
+                &[Type::VARCHAR],
+            )
+            .await?;
+        // on the write host, an impression flushed a moment ago may not be replicated yet
+        let impressed_stmt = write_conn
+            .prepare_typed(
+                "SELECT EXISTS (SELECT 1 FROM impression WHERE request_id = $1 AND advertisement_id = $2);",
+                &[Type::VARCHAR, Type::INT4],
+            )
+            .await?;
+        let bots_stmt = TypedReadStatement::new(
+            "SELECT pattern FROM bot_user_agent ORDER BY pattern",
+            [].into_iter(),
+        );
+
+        Ok(Queries {
+            delete_bots_stmt,
+            insert_bot_stmt,
+            impressed_stmt,
+            bots_stmt,
+        })
+    }
+}
+
+impl Queries {
+    pub async fn bots(&self, read: &Connection<'_>) -> Result<Vec<String>, tokio_postgres::Error> {
+        let rows = self.bots_stmt.query(read, [].into_iter()).await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+    pub async fn replace_bots(
+        &self,
+        write: &Transaction<'_>,
+        patterns: &[String],
+    ) -> Result<(), tokio_postgres::Error> {
+        write.execute(&self.delete_bots_stmt, &[]).await?;
+        for pattern in patterns {
+            write.execute(&self.insert_bot_stmt, &[pattern]).await?;
+        }
+        Ok(())
+    }
+    /// whether the impression of the served advertisement was flushed
+    pub async fn impressed(
+        &self,
+        write: &Connection<'_>,
+        request_id: &str,
+        advertisement_id: i32,
+    ) -> Result<bool, tokio_postgres::Error> {
+        let row = write
+            .query_one(&self.impressed_stmt, &[&request_id, &advertisement_id])
+            .await?;
+        Ok(row.get(0))
+    }
+}
@@ -0,0 +1,51 @@
+use crate::database::Usage;
+use crate::delivery::CounterStore;
+use chrono::{NaiveDate, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// counters of a single instance, for running without replicas
+#[derive(Default)]
+pub struct MemoryStore(Mutex<(NaiveDate, HashMap<i32, Usage>)>);
+
+impl MemoryStore {
+    fn rollover(state: &mut (NaiveDate, HashMap<i32, Usage>)) {
+        let today = Utc::now().date_naive();
+        if state.0 != today {
+            state.0 = today;
+            for usage in state.1.values_mut() {
+                usage.daily_impressions = 0;
+                usage.daily_spend = 0;
+            }
+        }
+    }
+}
+
+impl CounterStore for MemoryStore {
+    fn usage(&self, id: i32) -> Usage {
+        let mut state = self.0.lock().unwrap();
+        Self::rollover(&mut state);
+        state.1.get(&id).copied().unwrap_or_default()
+    }
+    fn record(&self, id: i32, impressions: i64, spend: i64) {
+        let mut state = self.0.lock().unwrap();
+        Self::rollover(&mut state);
+        state.1.entry(id).or_default().add(&Usage {
+            impressions,
+            spend,
+            daily_impressions: impressions,
+            daily_spend: spend,
+            ..Default::default()
+        });
+    }
+    fn click(&self, id: i32, spend: i64) {
+        let mut state = self.0.lock().unwrap();
+        Self::rollover(&mut state);
+        state.1.entry(id).or_default().add(&Usage {
+            spend,
+            daily_spend: spend,
+            clicks: 1,
+            ..Default::default()
+        });
+    }
+}
@@ -0,0 +1,37 @@
+//! budgets, impression caps and pacing of delivered advertisements
+mod memory;
+pub mod pacing;
+mod postgres;
+
+use crate::database::{Client, Usage};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use memory::MemoryStore;
+pub use postgres::PostgresStore;
+
+/// where delivered impressions, spend and clicks are counted
+///
+/// Reads are served from local state, so that pacing does not add a round trip
+/// to the serving path; stores shared across replicas sync in the background.
+pub trait CounterStore: Send + Sync {
+    fn usage(&self, id: i32) -> Usage;
+    fn record(&self, id: i32, impressions: i64, spend: i64);
+    /// a click, charged `spend` under a CPC bid
+    fn click(&self, id: i32, spend: i64);
+}
+
+/// `DELIVERY_STORE=memory` counts per instance, otherwise counters are shared through postgres
+pub fn from_env(client: Arc<Client>) -> Arc<dyn CounterStore> {
+    match env::var("DELIVERY_STORE").as_deref() {
+        Ok("memory") => Arc::new(MemoryStore::default()),
+        _ => {
+            let interval = env::var("DELIVERY_SYNC_INTERVAL")
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(1000);
+            PostgresStore::new(client, Duration::from_millis(interval))
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! smooth delivery of daily budgets across the day
+use crate::database::{Budget, Usage};
+use chrono::{NaiveDateTime, Timelike};
+use rand::Rng;
+
+/// cost of a single impression at the given price per thousand
+pub fn cost(bid: i64) -> i64 {
+    bid / 1000
+}
+
+/// whether to serve an impression costing `cost`
+///
+/// Lifetime limits are hard stops. Daily limits are throttled: the chance to
+/// serve is the remaining daily allowance over the share of it the remaining
+/// time of the day would get under even delivery, so an advertisement ahead
+/// of schedule slows down instead of exhausting its budget in the first hours.
+pub fn allow(
+    budget: &Budget,
+    usage: &Usage,
+    cost: i64,
+    now: NaiveDateTime,
+    rng: &mut impl Rng,
+) -> bool {
+    let exceeded =
+        |cap: Option<i64>, used: i64, next: i64| cap.is_some_and(|cap| used + next > cap);
+    if exceeded(budget.total_budget, usage.spend, cost)
+        || exceeded(budget.impression_cap, usage.impressions, 1)
+        || exceeded(budget.daily_budget, usage.daily_spend, cost)
+        || exceeded(budget.daily_impression_cap, usage.daily_impressions, 1)
+    {
+        return false;
+    }
+
+    let remaining_time = 1.0 - now.num_seconds_from_midnight() as f64 / 86400.0;
+    let rate = |cap: Option<i64>, used: i64| match cap {
+        Some(cap) if cap > 0 => (cap - used) as f64 / (cap as f64 * remaining_time),
+        _ => 1.0,
+    };
+    let rate = rate(budget.daily_budget, usage.daily_spend)
+        .min(rate(budget.daily_impression_cap, usage.daily_impressions));
+    rate >= 1.0 || rng.gen::<f64>() < rate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn at(hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+    }
+
+    /// share of `rounds` draws allowed
+    fn share(budget: &Budget, usage: &Usage, now: NaiveDateTime) -> f64 {
+        let mut rng = StdRng::seed_from_u64(1);
+        let rounds = 20000;
+        let allowed = (0..rounds)
+            .filter(|_| allow(budget, usage, 10, now, &mut rng))
+            .count();
+        allowed as f64 / rounds as f64
+    }
+
+    #[test]
+    fn unlimited_is_always_served() {
+        let usage = Usage {
+            spend: i64::MAX / 2,
+            impressions: i64::MAX / 2,
+            ..Default::default()
+        };
+        assert_eq!(share(&Budget::default(), &usage, at(23)), 1.0);
+    }
+
+    #[test]
+    fn lifetime_limits_stop_delivery() {
+        let budget = Budget {
+            total_budget: Some(100),
+            impression_cap: Some(5),
+            ..Default::default()
+        };
+        let under = Usage {
+            spend: 90,
+            impressions: 4,
+            ..Default::default()
+        };
+        assert_eq!(share(&budget, &under, at(0)), 1.0);
+        // the next impression would exceed the budget
+        let spent = Usage { spend: 91, ..under };
+        assert_eq!(share(&budget, &spent, at(0)), 0.0);
+        let capped = Usage {
+            impressions: 5,
+            ..under
+        };
+        assert_eq!(share(&budget, &capped, at(0)), 0.0);
+    }
+
+    #[test]
+    fn daily_limits_stop_delivery() {
+        let budget = Budget {
+            daily_budget: Some(1000),
+            daily_impression_cap: Some(10),
+            ..Default::default()
+        };
+        let spent = Usage {
+            daily_spend: 995,
+            ..Default::default()
+        };
+        assert_eq!(share(&budget, &spent, at(23)), 0.0);
+        let capped = Usage {
+            daily_impressions: 10,
+            ..Default::default()
+        };
+        assert_eq!(share(&budget, &capped, at(23)), 0.0);
+    }
+
+    #[test]
+    fn on_schedule_is_served() {
+        let budget = Budget {
+            daily_budget: Some(2400),
+            ..Default::default()
+        };
+        // half the budget left with half the day left
+        let usage = Usage {
+            daily_spend: 1200,
+            ..Default::default()
+        };
+        assert_eq!(share(&budget, &usage, at(12)), 1.0);
+    }
+
+    #[test]
+    fn ahead_of_schedule_is_throttled() {
+        let budget = Budget {
+            daily_budget: Some(2400),
+            ..Default::default()
+        };
+        // a quarter of the budget left with half the day left, served half as often
+        let usage = Usage {
+            daily_spend: 1800,
+            ..Default::default()
+        };
+        let observed = share(&budget, &usage, at(12));
+        assert!((observed - 0.5).abs() < 0.02, "served {observed}");
+        // the stricter of both daily limits applies
+        let budget = Budget {
+            daily_impression_cap: Some(100),
+            ..budget
+        };
+        let usage = Usage {
+            daily_impressions: 90,
+            ..usage
+        };
+        let observed = share(&budget, &usage, at(12));
+        assert!((observed - 0.2).abs() < 0.02, "served {observed}");
+    }
+
+    #[test]
+    fn cost_is_per_impression() {
+        assert_eq!(cost(2_000_000), 2000);
+        assert_eq!(cost(999), 0);
+    }
+}
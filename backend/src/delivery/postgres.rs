@@ -0,0 +1,221 @@
+use crate::database::{Client, Usage};
+use crate::delivery::CounterStore;
+use chrono::{NaiveDate, NaiveDateTime, TimeDelta, Utc};
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+/// totals changed this long before the latest one read are read again, in case their
+/// transaction committed after a later one
+const RELOAD_OVERLAP: TimeDelta = TimeDelta::seconds(10);
+
+/// counters shared by every replica through the `delivery_counter` and `delivery_total` tables
+///
+/// Local increments are flushed and the totals changed by any replica are reloaded
+/// every sync interval, so usage lags the other replicas by at most that long.
+#[derive(Default)]
+pub struct PostgresStore {
+    /// one lock, so that an increment moving between maps is never missed nor counted twice
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    synced: HashMap<i32, Usage>,
+    pending: HashMap<i32, Usage>,
+    /// being flushed
+    flushing: HashMap<i32, Usage>,
+    /// flushed but not reloaded yet
+    flushed: HashMap<i32, Usage>,
+    /// latest change reloaded
+    watermark: NaiveDateTime,
+    /// UTC day of `synced`, every total is reloaded on the next one for its daily usage
+    day: NaiveDate,
+}
+
+fn merge(into: &mut HashMap<i32, Usage>, from: HashMap<i32, Usage>) {
+    for (id, usage) in from {
+        into.entry(id).or_default().add(&usage);
+    }
+}
+
+impl PostgresStore {
+    pub fn new(client: Arc<Client>, interval: Duration) -> Arc<Self> {
+        let store = Arc::new(Self::default());
+        let weak = Arc::downgrade(&store);
+        tokio::spawn(async move { Self::sync_loop(weak, client, interval).await });
+        store
+    }
+    async fn sync_loop(store: Weak<Self>, client: Arc<Client>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let Some(store) = store.upgrade() else {
+                return;
+            };
+            if let Err(err) = store.sync(&client).await {
+                tracing::warn!("failed to sync delivery counters: {:?}", err);
+            }
+        }
+    }
+    async fn sync(&self, client: &Client) -> Result<(), tokio_postgres::Error> {
+        let flushing = self.start_flush();
+        if !flushing.is_empty() {
+            let recorded = client.record_delivery(&flushing).await;
+            self.end_flush(recorded.is_ok());
+            recorded?;
+        }
+
+        let today = Utc::now().date_naive();
+        let since = {
+            let state = self.state.lock().unwrap();
+            if state.day == today {
+                state.watermark - RELOAD_OVERLAP
+            } else {
+                NaiveDateTime::default()
+            }
+        };
+        // read from the write host, which already holds the increments just flushed
+        let (usage, watermark) = client.delivery_usage(since).await?;
+        self.reloaded(usage, watermark, today);
+        Ok(())
+    }
+    /// move the pending increments to `flushing`, returning them
+    fn start_flush(&self) -> HashMap<i32, Usage> {
+        let mut state = self.state.lock().unwrap();
+        state.flushing = mem::take(&mut state.pending);
+        state.flushing.clone()
+    }
+    /// count the flushed increments until they are reloaded, or keep them for the next
+    /// attempt
+    fn end_flush(&self, recorded: bool) {
+        let mut state = self.state.lock().unwrap();
+        let flushing = mem::take(&mut state.flushing);
+        if recorded {
+            merge(&mut state.flushed, flushing);
+        } else {
+            merge(&mut state.pending, flushing);
+        }
+    }
+    /// replace the reloaded totals, which hold every increment flushed before
+    fn reloaded(&self, usage: HashMap<i32, Usage>, watermark: NaiveDateTime, day: NaiveDate) {
+        let mut state = self.state.lock().unwrap();
+        if state.day != day {
+            state.synced.clear();
+            state.day = day;
+        }
+        state.synced.extend(usage);
+        state.watermark = watermark;
+        state.flushed.clear();
+    }
+}
+
+impl CounterStore for PostgresStore {
+    fn usage(&self, id: i32) -> Usage {
+        let state = self.state.lock().unwrap();
+        let mut usage = state.synced.get(&id).copied().unwrap_or_default();
+        for local in [&state.flushed, &state.flushing, &state.pending] {
+            if let Some(x) = local.get(&id) {
+                usage.add(x);
+            }
+        }
+        usage
+    }
+    fn record(&self, id: i32, impressions: i64, spend: i64) {
+        self.state
+            .lock()
+            .unwrap()
+            .pending
+            .entry(id)
+            .or_default()
+            .add(&Usage {
+                impressions,
+                spend,
+                daily_impressions: impressions,
+                daily_spend: spend,
+                ..Default::default()
+            });
+    }
+    fn click(&self, id: i32, spend: i64) {
+        self.state
+            .lock()
+            .unwrap()
+            .pending
+            .entry(id)
+            .or_default()
+            .add(&Usage {
+                spend,
+                daily_spend: spend,
+                clicks: 1,
+                ..Default::default()
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(impressions: i64) -> Usage {
+        Usage {
+            impressions,
+            daily_impressions: impressions,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn increments_stay_counted_once_across_a_sync() {
+        let store = PostgresStore::default();
+        let today = Utc::now().date_naive();
+        store.record(1, 2, 0);
+        assert_eq!(store.start_flush().get(&1), Some(&usage(2)));
+        store.record(1, 1, 0);
+        assert_eq!(store.usage(1).impressions, 3);
+
+        store.end_flush(true);
+        assert_eq!(store.usage(1).impressions, 3);
+        // the reloaded total holds the flushed increments, not the pending one
+        store.reloaded(
+            HashMap::from([(1, usage(12))]),
+            NaiveDateTime::default(),
+            today,
+        );
+        assert_eq!(store.usage(1).impressions, 13);
+    }
+
+    #[test]
+    fn failed_flushes_are_retried() {
+        let store = PostgresStore::default();
+        store.record(1, 2, 0);
+        store.start_flush();
+        store.end_flush(false);
+        assert_eq!(store.usage(1).impressions, 2);
+        assert_eq!(store.start_flush().get(&1), Some(&usage(2)));
+    }
+
+    #[test]
+    fn a_new_day_reloads_every_total() {
+        let store = PostgresStore::default();
+        let today = Utc::now().date_naive();
+        let reloaded = HashMap::from([(1, usage(5)), (2, usage(7))]);
+        store.reloaded(reloaded, NaiveDateTime::default(), today);
+        // only the totals changed since are read on the same day
+        store.reloaded(
+            HashMap::from([(2, usage(8))]),
+            NaiveDateTime::default(),
+            today,
+        );
+        assert_eq!(store.usage(1).daily_impressions, 5);
+        assert_eq!(store.usage(2).daily_impressions, 8);
+
+        let tomorrow = today.succ_opt().unwrap();
+        store.reloaded(
+            HashMap::from([(2, usage(8))]),
+            NaiveDateTime::default(),
+            tomorrow,
+        );
+        assert_eq!(store.usage(1), Usage::default());
+    }
+}
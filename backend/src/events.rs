@@ -0,0 +1,375 @@
+//! tracking events queued in memory, appended to postgres in batches and spilled to disk
+//! while it is unreachable
+use crate::database::{Client, Event};
+use std::env;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::sync::mpsc;
+
+/// numbers spill files written in the same instant
+static SPILL_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+pub struct Config {
+    /// events waiting for a flush, before `push` waits
+    capacity: usize,
+    batch_size: usize,
+    flush_interval: Duration,
+    /// how long `push` waits on a full queue
+    push_timeout: Duration,
+    /// how long a batch may take before it is spilled
+    flush_timeout: Duration,
+    spill_dir: PathBuf,
+}
+
+impl Config {
+    /// read from the environment, each queue uses the same settings
+    ///
+    /// - `EVENT_QUEUE_CAPACITY`, 10000 when unset
+    /// - `EVENT_BATCH_SIZE`, 1000 when unset
+    /// - `EVENT_FLUSH_INTERVAL` in milliseconds, 1000 when unset
+    /// - `EVENT_PUSH_TIMEOUT` in milliseconds, 100 when unset
+    /// - `EVENT_FLUSH_TIMEOUT` in milliseconds, 5000 when unset
+    /// - `EVENT_SPILL_DIR`, `events` in the temporary directory when unset
+    pub fn from_env() -> Self {
+        let capacity = env::var("EVENT_QUEUE_CAPACITY")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(10000);
+        let batch_size = env::var("EVENT_BATCH_SIZE")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(1000);
+        let flush_interval = env::var("EVENT_FLUSH_INTERVAL")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(1000);
+        let push_timeout = env::var("EVENT_PUSH_TIMEOUT")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(100);
+        let flush_timeout = env::var("EVENT_FLUSH_TIMEOUT")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(5000);
+        let spill_dir = env::var("EVENT_SPILL_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| env::temp_dir().join("events"));
+        Self {
+            capacity: usize::max(capacity, 1),
+            batch_size: usize::max(batch_size, 1),
+            flush_interval: Duration::from_millis(flush_interval),
+            push_timeout: Duration::from_millis(push_timeout),
+            flush_timeout: Duration::from_millis(flush_timeout),
+            spill_dir,
+        }
+    }
+    /// longest a pushed event waits before it is appended or spilled
+    pub fn flush_delay(&self) -> Duration {
+        self.flush_interval + self.flush_timeout
+    }
+}
+
+/// called with every event appended for the first time, once its batch is committed
+pub type OnAppended<E> = Box<dyn Fn(&E) + Send + Sync>;
+
+/// the queue stayed full for the push timeout, the event was dropped
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Backpressure;
+
+/// bounded queue of events of one table, flushed by a background worker
+///
+/// An event is durable once flushed or spilled, those still queued are lost with the
+/// process. A spilled batch may be appended twice when the process stops while replaying
+/// it, [`Event`] tables skip the repeated rows. Whatever counts the events does so in
+/// `appended`, so that an event repeated on any backend is counted once.
+pub struct Queue<E> {
+    sender: mpsc::Sender<E>,
+    push_timeout: Duration,
+}
+
+impl<E: Event> Queue<E> {
+    pub fn new(client: Arc<Client>, config: &Config, appended: OnAppended<E>) -> Self {
+        let (sender, receiver) = mpsc::channel(config.capacity);
+        let worker = Worker {
+            client,
+            receiver,
+            appended,
+            batch_size: config.batch_size,
+            flush_interval: config.flush_interval,
+            flush_timeout: config.flush_timeout,
+            spill_dir: config.spill_dir.join(E::TABLE),
+            // batches spilled before a restart are replayed too
+            spilled: true,
+        };
+        tokio::spawn(worker.run());
+        Self {
+            sender,
+            push_timeout: config.push_timeout,
+        }
+    }
+    /// queue the event, waiting up to the push timeout while the queue is full
+    pub async fn push(&self, event: E) -> Result<(), Backpressure> {
+        match tokio::time::timeout(self.push_timeout, self.sender.send(event)).await {
+            Ok(Ok(())) => Ok(()),
+            _ => {
+                tracing::warn!(counter.events.rejected = 1, table = E::TABLE);
+                Err(Backpressure)
+            }
+        }
+    }
+}
+
+struct Worker<E> {
+    client: Arc<Client>,
+    receiver: mpsc::Receiver<E>,
+    appended: OnAppended<E>,
+    batch_size: usize,
+    flush_interval: Duration,
+    flush_timeout: Duration,
+    /// of this table
+    spill_dir: PathBuf,
+    /// whether `spill_dir` may hold batches to replay
+    spilled: bool,
+}
+
+impl<E: Event> Worker<E> {
+    /// flush every full batch, and what was received every flush interval
+    async fn run(mut self) {
+        let mut interval = tokio::time::interval(self.flush_interval);
+        let mut batch = Vec::with_capacity(self.batch_size);
+        loop {
+            let limit = self.batch_size - batch.len();
+            tokio::select! {
+                received = self.receiver.recv_many(&mut batch, limit) => {
+                    if received == 0 {
+                        // every sender is dropped
+                        self.flush(mem::take(&mut batch)).await;
+                        return;
+                    }
+                    if batch.len() < self.batch_size {
+                        continue;
+                    }
+                }
+                _ = interval.tick() => {}
+            }
+            if !batch.is_empty() && !self.flush(mem::take(&mut batch)).await {
+                continue;
+            }
+            if self.spilled {
+                self.replay().await;
+            }
+        }
+    }
+    /// append the batch, or spill it; whether it was appended
+    async fn flush(&mut self, batch: Vec<E>) -> bool {
+        if batch.is_empty() {
+            return true;
+        }
+        match tokio::time::timeout(self.flush_timeout, self.client.copy_events(&batch)).await {
+            Ok(Ok(appended)) => {
+                tracing::info!(
+                    counter.events.flushed = batch.len() as u64,
+                    table = E::TABLE
+                );
+                self.report(&batch, &appended);
+                return true;
+            }
+            Ok(Err(err)) => tracing::warn!("failed to flush {} events: {:?}", E::TABLE, err),
+            Err(_) => tracing::warn!("flushing {} events timed out", E::TABLE),
+        }
+        match spill(&self.spill_dir, &batch).await {
+            Ok(()) => {
+                tracing::info!(
+                    counter.events.spilled = batch.len() as u64,
+                    table = E::TABLE
+                );
+                self.spilled = true;
+            }
+            Err(err) => tracing::error!(
+                "failed to spill {} {} events, dropping them: {:?}",
+                batch.len(),
+                E::TABLE,
+                err
+            ),
+        }
+        false
+    }
+    fn report(&self, batch: &[E], appended: &[usize]) {
+        let repeated = batch.len() - appended.len();
+        if repeated > 0 {
+            tracing::info!(counter.events.repeated = repeated as u64, table = E::TABLE);
+        }
+        for &i in appended {
+            (self.appended)(&batch[i]);
+        }
+    }
+    /// append spilled batches, oldest first, for at most half a flush interval so that
+    /// the queue keeps draining
+    async fn replay(&mut self) {
+        let started = Instant::now();
+        let files = match spilled_files(&self.spill_dir).await {
+            Ok(x) => x,
+            Err(err) => {
+                tracing::warn!("failed to list spilled {} events: {:?}", E::TABLE, err);
+                return;
+            }
+        };
+        for path in &files {
+            if started.elapsed() > self.flush_interval / 2 {
+                return;
+            }
+            let batch = match read_spilled::<E>(path).await {
+                Ok(x) => x,
+                Err(err) => {
+                    tracing::warn!("failed to read spilled events {:?}: {:?}", path, err);
+                    return;
+                }
+            };
+            let copied = tokio::time::timeout(self.flush_timeout, self.client.copy_events(&batch));
+            match copied.await {
+                Ok(Ok(appended)) => {
+                    tracing::info!(
+                        counter.events.replayed = batch.len() as u64,
+                        table = E::TABLE
+                    );
+                    self.report(&batch, &appended);
+                    if let Err(err) = fs::remove_file(path).await {
+                        tracing::warn!("failed to remove replayed {:?}: {:?}", path, err);
+                        return;
+                    }
+                }
+                // still unreachable, retried after the next flush
+                _ => return,
+            }
+        }
+        self.spilled = false;
+    }
+}
+
+/// write the batch as JSON lines into `dir`, renamed once complete so that replay never
+/// reads a partial file
+async fn spill<E: Event>(dir: &Path, batch: &[E]) -> std::io::Result<()> {
+    let mut lines = Vec::new();
+    for event in batch {
+        serde_json::to_writer(&mut lines, event)?;
+        lines.push(b'\n');
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let sequence = SPILL_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let path = dir.join(format!("{nanos:020}-{sequence:010}.jsonl"));
+    let partial = path.with_extension("jsonl.tmp");
+    fs::create_dir_all(dir).await?;
+    fs::write(&partial, lines).await?;
+    fs::rename(&partial, &path).await
+}
+
+/// complete spill files, in the order they were written
+async fn spilled_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(x) => x,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    let mut files = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|x| x == "jsonl") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// events of a spill file, skipping lines that do not parse
+async fn read_spilled<E: Event>(path: &Path) -> std::io::Result<Vec<E>> {
+    let content = fs::read_to_string(path).await?;
+    Ok(content
+        .lines()
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(x) => Some(x),
+            Err(err) => {
+                tracing::warn!("skipping spilled event of {:?}: {:?}", path, err);
+                None
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use tokio_postgres::types::{ToSql, Type};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Row {
+        id: i32,
+    }
+
+    impl Event for Row {
+        const TABLE: &'static str = "row";
+        const KEY: &'static [&'static str] = &["id"];
+        const COLUMNS: &'static [(&'static str, Type)] = &[("id", Type::INT4)];
+        fn values(&self) -> Vec<Box<dyn ToSql + Sync + Send>> {
+            vec![Box::new(self.id)]
+        }
+    }
+
+    fn rows(ids: &[i32]) -> Vec<Row> {
+        ids.iter().map(|&id| Row { id }).collect()
+    }
+
+    /// a directory of its own per test
+    fn dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("events-{name}-{:016x}", rand::random::<u64>()))
+    }
+
+    #[tokio::test]
+    async fn spilled_batches_read_back_in_order() {
+        let dir = dir("order");
+        spill(&dir, &rows(&[1, 2])).await.unwrap();
+        spill(&dir, &rows(&[3])).await.unwrap();
+
+        let files = spilled_files(&dir).await.unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(read_spilled::<Row>(&files[0]).await.unwrap(), rows(&[1, 2]));
+        assert_eq!(read_spilled::<Row>(&files[1]).await.unwrap(), rows(&[3]));
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn partial_spill_files_are_not_replayed() {
+        let dir = dir("partial");
+        fs::create_dir_all(&dir).await.unwrap();
+        fs::write(dir.join("0-0.jsonl.tmp"), "{\"id\":1}\n")
+            .await
+            .unwrap();
+        assert!(spilled_files(&dir).await.unwrap().is_empty());
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_spill_dir_has_nothing_to_replay() {
+        assert!(spilled_files(&dir("missing")).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn malformed_spilled_lines_are_skipped() {
+        let dir = dir("malformed");
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("0-0.jsonl");
+        fs::write(&path, "{\"id\":1}\n{\"id\":\n{\"id\":2}\n")
+            .await
+            .unwrap();
+        assert_eq!(read_spilled::<Row>(&path).await.unwrap(), rows(&[1, 2]));
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+//! traffic splits between advertisement variants of running experiments
+use crate::database::{Client, Metrics};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::mem;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::Duration;
+
+/// how an advertisement takes part in the experiments for one user
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Split {
+    /// not a variant of any running experiment
+    Unsplit,
+    /// the variant the user is assigned to in this experiment
+    Variant(i32),
+    /// a variant assigned to other users
+    Excluded,
+}
+
+/// running experiments of every replica, reloaded with their metrics every sync interval
+#[derive(Default)]
+pub struct Experiments {
+    /// experiment id and bucket range of each variant, by advertisement id
+    running: RwLock<HashMap<i32, (i32, Range<u64>)>>,
+    /// metrics by `(experiment id, advertisement id)` not flushed yet
+    pending: Mutex<HashMap<(i32, i32), Metrics>>,
+}
+
+impl Experiments {
+    /// `EXPERIMENT_SYNC_INTERVAL` in milliseconds, 1000 when unset
+    pub fn from_env(client: Arc<Client>) -> Arc<Self> {
+        let interval = env::var("EXPERIMENT_SYNC_INTERVAL")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(1000);
+        let experiments = Arc::new(Self::default());
+        let weak = Arc::downgrade(&experiments);
+        tokio::spawn(async move {
+            Self::sync_loop(weak, client, Duration::from_millis(interval)).await
+        });
+        experiments
+    }
+    async fn sync_loop(experiments: Weak<Self>, client: Arc<Client>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let Some(experiments) = experiments.upgrade() else {
+                return;
+            };
+            if let Err(err) = experiments.sync(&client).await {
+                tracing::warn!("failed to sync experiments: {:?}", err);
+            }
+        }
+    }
+    async fn sync(&self, client: &Client) -> Result<(), tokio_postgres::Error> {
+        let pending = mem::take(&mut *self.pending.lock().unwrap());
+        if !pending.is_empty() {
+            if let Err(err) = client.record_experiments(&pending).await {
+                // keep the metrics for the next attempt
+                let mut current = self.pending.lock().unwrap();
+                for (key, metrics) in pending {
+                    current.entry(key).or_default().add(&metrics);
+                }
+                return Err(err);
+            }
+        }
+
+        // variants come ordered by experiment, each takes the next `traffic` percent of users
+        let mut running = HashMap::new();
+        let mut current = None;
+        let mut start = 0;
+        for (experiment_id, advertisement_id, traffic) in client.running_experiments().await? {
+            if current != Some(experiment_id) {
+                current = Some(experiment_id);
+                start = 0;
+            }
+            let end = start + traffic.max(0) as u64;
+            running.insert(advertisement_id, (experiment_id, start..end));
+            start = end;
+        }
+        *self.running.write().unwrap() = running;
+        Ok(())
+    }
+    /// split of the advertisement `id` for the user identified by `subject`
+    pub fn split(&self, id: i32, subject: &str) -> Split {
+        match self.running.read().unwrap().get(&id) {
+            None => Split::Unsplit,
+            Some((experiment_id, range)) if range.contains(&bucket(*experiment_id, subject)) => {
+                Split::Variant(*experiment_id)
+            }
+            Some(_) => Split::Excluded,
+        }
+    }
+    pub fn record(&self, experiment_id: i32, id: i32, metrics: &Metrics) {
+        self.pending
+            .lock()
+            .unwrap()
+            .entry((experiment_id, id))
+            .or_default()
+            .add(metrics);
+    }
+}
+
+/// stable percentile of a user in an experiment, independent across experiments
+fn bucket(experiment_id: i32, subject: &str) -> u64 {
+    let hash = Sha256::new()
+        .chain_update(experiment_id.to_be_bytes())
+        .chain_update(subject)
+        .finalize();
+    u64::from_be_bytes(hash[..8].try_into().unwrap()) % 100
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn experiments(variants: &[(i32, i32, Range<u64>)]) -> Experiments {
+        let experiments = Experiments::default();
+        *experiments.running.write().unwrap() = variants
+            .iter()
+            .map(|(experiment_id, id, range)| (*id, (*experiment_id, range.clone())))
+            .collect();
+        experiments
+    }
+
+    #[test]
+    fn bucket_is_stable() {
+        for subject in ["alice", "bob", ""] {
+            assert_eq!(bucket(7, subject), bucket(7, subject));
+            assert!(bucket(7, subject) < 100);
+        }
+        // a change of the hash would reassign every running experiment
+        assert_eq!(bucket(1, "alice"), 47);
+    }
+
+    #[test]
+    fn buckets_are_independent_across_experiments() {
+        let subjects: Vec<_> = (0..1000).map(|x| format!("user-{x}")).collect();
+        let same = subjects
+            .iter()
+            .filter(|x| bucket(1, x) == bucket(2, x))
+            .count();
+        // about one in a hundred by chance
+        assert!(same < 30, "{same} users share their bucket");
+    }
+
+    #[test]
+    fn buckets_are_uniform() {
+        let mut counts = [0; 10];
+        for x in 0..10000 {
+            counts[bucket(1, &format!("user-{x}")) as usize / 10] += 1;
+        }
+        assert!(counts.iter().all(|x| (800..1200).contains(x)), "{counts:?}");
+    }
+
+    #[test]
+    fn split_assigns_one_variant() {
+        let experiments = experiments(&[(1, 10, 0..50), (1, 11, 50..100)]);
+        for x in 0..100 {
+            let subject = format!("user-{x}");
+            let splits = [10, 11].map(|id| experiments.split(id, &subject));
+            let variants = splits.iter().filter(|x| **x == Split::Variant(1)).count();
+            assert_eq!(variants, 1, "{splits:?}");
+            assert_eq!(splits, [10, 11].map(|id| experiments.split(id, &subject)));
+        }
+        assert_eq!(experiments.split(12, "user-1"), Split::Unsplit);
+    }
+}
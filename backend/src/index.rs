@@ -0,0 +1,419 @@
+//! in-memory inverted index of servable advertisements, answering `GET /ad` without postgres
+use crate::database::{After, Client, Condition, PartialAdvertisement, Sort, Status, Targeted};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use common::{Country, Platform};
+use roaring::RoaringBitmap;
+use std::collections::HashMap;
+use std::env;
+use std::ops::Range;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+
+/// rows changed this long before the last seen change are loaded again, in case their
+/// transaction committed after a later one
+const REFRESH_OVERLAP: TimeDelta = TimeDelta::seconds(10);
+
+pub struct Index {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// slot of each indexed advertisement
+    slots: HashMap<i32, u32>,
+    entries: Vec<Option<Targeted>>,
+    free: Vec<u32>,
+    paid: RoaringBitmap,
+    house: RoaringBitmap,
+    /// keyed by the stored id, 0 for advertisements without a country
+    country: HashMap<i32, RoaringBitmap>,
+    platform: HashMap<Option<i32>, RoaringBitmap>,
+    gender: HashMap<Option<i32>, RoaringBitmap>,
+    age: AgeIndex,
+    /// latest `updated_at` loaded
+    watermark: NaiveDateTime,
+    /// earliest `end_at` indexed
+    next_expiry: Option<NaiveDateTime>,
+}
+
+/// elementary intervals between every bound of an age range
+///
+/// `segments[i]` holds the advertisements covering `[bounds[i - 1], bounds[i])`, with
+/// the first and last segment unbounded below and above.
+struct AgeIndex {
+    bounds: Vec<i32>,
+    segments: Vec<RoaringBitmap>,
+}
+
+impl Default for AgeIndex {
+    fn default() -> Self {
+        Self {
+            bounds: Vec::new(),
+            segments: vec![RoaringBitmap::new()],
+        }
+    }
+}
+
+impl AgeIndex {
+    /// add `bound`, splitting the segment covering it in two with the same members
+    fn split(&mut self, bound: i32) {
+        let p = self.bounds.partition_point(|x| *x < bound);
+        if self.bounds.get(p) != Some(&bound) {
+            self.bounds.insert(p, bound);
+            self.segments.insert(p, self.segments[p].clone());
+        }
+    }
+    /// segments covered by a range with an exclusive upper bound
+    fn segments(&mut self, (lower, upper): (Option<i32>, Option<i32>)) -> Range<usize> {
+        for bound in [lower, upper].into_iter().flatten() {
+            self.split(bound);
+        }
+        let segment = |bound: i32| self.bounds.partition_point(|x| *x < bound) + 1;
+        let start = lower.map_or(0, segment);
+        let end = upper.map_or(self.segments.len(), segment);
+        start..end.max(start)
+    }
+    fn insert(&mut self, range: (Option<i32>, Option<i32>), slot: u32) {
+        for i in self.segments(range) {
+            self.segments[i].insert(slot);
+        }
+    }
+    fn remove(&mut self, range: (Option<i32>, Option<i32>), slot: u32) {
+        for i in self.segments(range) {
+            self.segments[i].remove(slot);
+        }
+    }
+    fn get(&self, age: i32) -> &RoaringBitmap {
+        &self.segments[self.bounds.partition_point(|x| *x <= age)]
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, id: i32) {
+        let Some(slot) = self.slots.remove(&id) else {
+            return;
+        };
+        let Some(entry) = self.entries[slot as usize].take() else {
+            return;
+        };
+        self.paid.remove(slot);
+        self.house.remove(slot);
+        for bitmap in [
+            self.country.get_mut(&entry.country),
+            self.platform.get_mut(&entry.platform),
+            self.gender.get_mut(&entry.gender),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            bitmap.remove(slot);
+        }
+        if let Some(range) = entry.age_range {
+            self.age.remove(range, slot);
+        }
+        self.free.push(slot);
+    }
+    fn apply(&mut self, entry: Targeted, now: NaiveDateTime) {
+        self.watermark = self.watermark.max(entry.updated_at);
+        self.remove(entry.partial.id);
+        if entry.status != Status::Active || entry.partial.end_at <= now {
+            return;
+        }
+
+        let slot = self.free.pop().unwrap_or_else(|| {
+            self.entries.push(None);
+            (self.entries.len() - 1) as u32
+        });
+        self.slots.insert(entry.partial.id, slot);
+        if entry.house {
+            self.house.insert(slot);
+        } else {
+            self.paid.insert(slot);
+        }
+        self.country.entry(entry.country).or_default().insert(slot);
+        self.platform
+            .entry(entry.platform)
+            .or_default()
+            .insert(slot);
+        self.gender.entry(entry.gender).or_default().insert(slot);
+        if let Some(range) = entry.age_range {
+            self.age.insert(range, slot);
+        }
+        let end_at = entry.partial.end_at;
+        self.next_expiry = Some(self.next_expiry.map_or(end_at, |x| x.min(end_at)));
+        self.entries[slot as usize] = Some(entry);
+    }
+    /// drop the advertisements that ended by `now`, nothing changes them once expired
+    fn remove_expired(&mut self, now: NaiveDateTime) {
+        if self.next_expiry.is_none_or(|x| x > now) {
+            return;
+        }
+        let (expired, live): (Vec<_>, Vec<_>) = self
+            .entries
+            .iter()
+            .flatten()
+            .map(|x| (x.partial.id, x.partial.end_at))
+            .partition(|(_, end_at)| *end_at <= now);
+        self.next_expiry = live.into_iter().map(|(_, end_at)| end_at).min();
+        for (id, _) in expired {
+            self.remove(id);
+        }
+    }
+    /// unexpired advertisements in `set`, ordered by `sort`
+    fn collect(&self, set: &RoaringBitmap, sort: Sort) -> Vec<&PartialAdvertisement> {
+        let now = Utc::now().naive_utc();
+        let mut ads: Vec<_> = set
+            .iter()
+            .filter_map(|slot| self.entries[slot as usize].as_ref())
+            .map(|x| &x.partial)
+            .filter(|x| x.end_at > now)
+            .collect();
+        ads.sort_by(|a, b| sort.compare(&a.position(), &b.position()));
+        ads
+    }
+}
+
+fn get<K: Eq + std::hash::Hash>(map: &HashMap<K, RoaringBitmap>, key: &K) -> RoaringBitmap {
+    map.get(key).cloned().unwrap_or_default()
+}
+
+impl Index {
+    /// `SERVING_INDEX=postgres` queries postgres on every cache miss instead
+    ///
+    /// The index is loaded before returning, then refreshed every
+    /// `INDEX_REFRESH_INTERVAL` milliseconds, 1000 when unset.
+    pub async fn from_env(client: Arc<Client>) -> Option<Arc<Self>> {
+        if env::var("SERVING_INDEX").as_deref() == Ok("postgres") {
+            return None;
+        }
+        let interval = env::var("INDEX_REFRESH_INTERVAL")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(1000);
+        let index = Arc::new(Self {
+            inner: RwLock::new(Inner::default()),
+        });
+        index
+            .refresh(&client)
+            .await
+            .expect("failed to load advertisement index");
+        let weak = Arc::downgrade(&index);
+        tokio::spawn(async move {
+            Self::refresh_loop(weak, client, Duration::from_millis(interval)).await
+        });
+        Some(index)
+    }
+    async fn refresh_loop(index: Weak<Self>, client: Arc<Client>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let Some(index) = index.upgrade() else {
+                return;
+            };
+            if let Err(err) = index.refresh(&client).await {
+                tracing::warn!("failed to refresh advertisement index: {:?}", err);
+            }
+        }
+    }
+    /// apply the current state of the advertisements `ids`
+    pub async fn reload(&self, client: &Client, ids: &[i32]) -> Result<(), tokio_postgres::Error> {
+        let changed = client.targeted(ids).await?;
+        let now = Utc::now().naive_utc();
+        let mut inner = self.inner.write().unwrap();
+        for entry in changed {
+            inner.apply(entry, now);
+        }
+        Ok(())
+    }
+    /// load every advertisement again, for changes that might have been missed
+    pub async fn resync(&self, client: &Client) -> Result<(), tokio_postgres::Error> {
+        let changed = client.changed_since(NaiveDateTime::default()).await?;
+        let now = Utc::now().naive_utc();
+        let mut inner = self.inner.write().unwrap();
+        for entry in changed {
+            inner.apply(entry, now);
+        }
+        Ok(())
+    }
+    /// load the advertisements changed since the last refresh
+    async fn refresh(&self, client: &Client) -> Result<(), tokio_postgres::Error> {
+        let watermark = self.inner.read().unwrap().watermark;
+        let since = if watermark == NaiveDateTime::default() {
+            watermark
+        } else {
+            watermark - REFRESH_OVERLAP
+        };
+        let changed = client.changed_since(since).await?;
+        let now = Utc::now().naive_utc();
+        let mut inner = self.inner.write().unwrap();
+        inner.remove_expired(now);
+        for entry in changed {
+            inner.apply(entry, now);
+        }
+        Ok(())
+    }
+    /// same result as [`Client::query_partial`]
+    pub fn query_partial(
+        &self,
+        cond: Condition,
+        sort: Sort,
+        after: Option<After>,
+        (limit, offset): (usize, usize),
+    ) -> Vec<PartialAdvertisement> {
+        let inner = self.inner.read().unwrap();
+        let mut set = inner.paid.clone();
+        if let Some(x) = cond.country {
+            set &= get(&inner.country, &(x.into_id() as i32));
+        }
+        if let Some(x) = cond.platform {
+            set &= get(&inner.platform, &Some(x as i32));
+        }
+        if let Some(x) = cond.age {
+            set &= inner.age.get(x);
+        }
+        if let Some(x) = cond.gender {
+            set &= get(&inner.gender, &Some(x as i32));
+        }
+
+        inner
+            .collect(&set, sort)
+            .into_iter()
+            .filter(|x| after.is_none_or(|after| sort.compare(&x.position(), &after).is_gt()))
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+    /// same result as [`Client::query_house`]
+    pub fn query_house(
+        &self,
+        country: Option<Country>,
+        platform: Option<Platform>,
+        limit: usize,
+    ) -> Vec<PartialAdvertisement> {
+        let inner = self.inner.read().unwrap();
+        let mut set = inner.house.clone();
+        if let Some(x) = country {
+            set &= get(&inner.country, &0) | get(&inner.country, &(x.into_id() as i32));
+        }
+        if let Some(x) = platform {
+            set &= get(&inner.platform, &None) | get(&inner.platform, &Some(x as i32));
+        }
+
+        inner
+            .collect(&set, Sort::PriorityDesc)
+            .into_iter()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{BidType, Budget};
+
+    fn targeted(id: i32, end_at: NaiveDateTime) -> Targeted {
+        Targeted {
+            partial: PartialAdvertisement {
+                id,
+                title: format!("ad {id}"),
+                end_at,
+                priority: 0,
+                weight: 1,
+                bid: 1000,
+                budget: Budget::default(),
+                bid_type: BidType::Cpm,
+                advertiser: None,
+                category: None,
+            },
+            age_range: Some((None, None)),
+            country: 0,
+            platform: None,
+            gender: None,
+            status: Status::Active,
+            house: false,
+            updated_at: NaiveDateTime::default(),
+        }
+    }
+
+    #[test]
+    fn expired_advertisements_are_removed() {
+        let now = Utc::now().naive_utc();
+        let mut inner = Inner::default();
+        inner.apply(targeted(1, now + TimeDelta::seconds(10)), now);
+        inner.apply(targeted(2, now + TimeDelta::seconds(20)), now);
+        // already expired when loaded
+        inner.apply(targeted(3, now - TimeDelta::seconds(1)), now);
+        assert_eq!(inner.slots.len(), 2);
+
+        inner.remove_expired(now + TimeDelta::seconds(15));
+        assert_eq!(inner.slots.keys().collect::<Vec<_>>(), vec![&2]);
+        assert_eq!(inner.paid.len(), 1);
+        assert_eq!(inner.next_expiry, Some(now + TimeDelta::seconds(20)));
+        inner.remove_expired(now + TimeDelta::seconds(20));
+        assert!(inner.slots.is_empty() && inner.paid.is_empty());
+        assert_eq!(inner.next_expiry, None);
+    }
+
+    fn members(age: &AgeIndex, at: i32) -> Vec<u32> {
+        age.get(at).iter().collect()
+    }
+
+    #[test]
+    fn split_copies_members_once() {
+        let mut age = AgeIndex::default();
+        age.insert((None, None), 1);
+        age.split(18);
+        age.split(18);
+        assert_eq!(age.bounds, vec![18]);
+        assert_eq!(age.segments.len(), 2);
+        assert_eq!(members(&age, 17), vec![1]);
+        assert_eq!(members(&age, 18), vec![1]);
+    }
+
+    #[test]
+    fn upper_bound_is_exclusive() {
+        let mut age = AgeIndex::default();
+        age.insert((Some(18), Some(30)), 1);
+        assert_eq!(members(&age, 17), Vec::<u32>::new());
+        assert_eq!(members(&age, 18), vec![1]);
+        assert_eq!(members(&age, 29), vec![1]);
+        assert_eq!(members(&age, 30), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn unbounded_sides_reach_every_age() {
+        let mut age = AgeIndex::default();
+        age.insert((None, Some(18)), 1);
+        age.insert((Some(65), None), 2);
+        assert_eq!(members(&age, i32::MIN), vec![1]);
+        assert_eq!(members(&age, 17), vec![1]);
+        assert_eq!(members(&age, 40), Vec::<u32>::new());
+        assert_eq!(members(&age, 65), vec![2]);
+        assert_eq!(members(&age, i32::MAX), vec![2]);
+    }
+
+    #[test]
+    fn overlapping_ranges_share_segments() {
+        let mut age = AgeIndex::default();
+        age.insert((Some(18), Some(30)), 1);
+        age.insert((Some(25), Some(40)), 2);
+        assert_eq!(age.segments((Some(25), Some(30))), 2..3);
+        assert_eq!(members(&age, 20), vec![1]);
+        assert_eq!(members(&age, 25), vec![1, 2]);
+        assert_eq!(members(&age, 30), vec![2]);
+
+        age.remove((Some(18), Some(30)), 1);
+        assert_eq!(members(&age, 20), Vec::<u32>::new());
+        assert_eq!(members(&age, 25), vec![2]);
+    }
+
+    #[test]
+    fn empty_range_covers_no_segment() {
+        let mut age = AgeIndex::default();
+        let range = age.segments((Some(30), Some(18)));
+        assert!(range.is_empty());
+    }
+}
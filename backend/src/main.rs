@@ -1,6 +1,17 @@
+mod auction;
 mod database;
+mod delivery;
+mod events;
+mod experiment;
+mod index;
 mod logger;
+mod ranking;
 mod routes;
+mod separation;
+mod shared_cache;
+mod tracking;
+mod traffic;
+mod warm;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -0,0 +1,218 @@
+//! ordering of matched advertisements before pagination
+use rand::Rng;
+use rand_distr::{Beta, Distribution};
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, Hash, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Ranking {
+    /// highest priority first, oldest first among the same priority
+    #[default]
+    Priority,
+    /// highest priority first, random among the same priority in proportion to weight
+    Weighted,
+    /// highest expected price first, see [`crate::auction`]
+    Auction,
+    /// highest priority first, by click-through rate sampled from feedback among the same priority
+    ///
+    /// Feedback is only what tracking counts: impressions and clicks of sealed tokens,
+    /// appended once and not flagged as invalid traffic, so that nobody can steer the
+    /// ranking by reporting clicks.
+    Bandit,
+}
+
+pub trait Ranked {
+    fn priority(&self) -> i32;
+    fn weight(&self) -> i32;
+}
+
+/// weighted random permutation within each priority tier
+///
+/// Each item draws the key `u^(1/weight)` (Efraimidis-Spirakis), so that the
+/// probability of being placed first is proportional to its weight.
+pub fn weighted<T: Ranked>(items: &mut Vec<T>, rng: &mut impl Rng) {
+    let mut keyed: Vec<_> = items
+        .drain(..)
+        .map(|item| {
+            let key = rng.gen::<f64>().powf(1.0 / item.weight().max(1) as f64);
+            (item.priority(), key, item)
+        })
+        .collect();
+    keyed.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.total_cmp(&a.1)));
+    items.extend(keyed.into_iter().map(|(_, _, item)| item));
+}
+
+/// observed feedback of an item, as counted by [`crate::delivery`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Trials {
+    pub impressions: i64,
+    pub clicks: i64,
+}
+
+/// Thompson sampling within each priority tier
+///
+/// Each item draws a click-through rate from `Beta(1 + clicks, 1 + misses)`, so
+/// items with few impressions still get explored while the best ones win most draws.
+pub fn thompson<T: Ranked>(items: &mut Vec<T>, trials: impl Fn(&T) -> Trials, rng: &mut impl Rng) {
+    let mut keyed: Vec<_> = items
+        .drain(..)
+        .map(|item| {
+            let Trials {
+                impressions,
+                clicks,
+            } = trials(&item);
+            let clicks = clicks.clamp(0, impressions.max(0));
+            let misses = impressions.max(0) - clicks;
+            let key = Beta::new(1.0 + clicks as f64, 1.0 + misses as f64)
+                .map_or(0.0, |beta| beta.sample(rng));
+            (item.priority(), key, item)
+        })
+        .collect();
+    keyed.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.total_cmp(&a.1)));
+    items.extend(keyed.into_iter().map(|(_, _, item)| item));
+}
+
+/// default ranking per placement, read from `PLACEMENT_RANKING`
+///
+/// format: `home=weighted,feed=priority`
+#[derive(Default)]
+pub struct Placements(HashMap<String, Ranking>);
+
+impl Placements {
+    pub fn from_env() -> Self {
+        let Ok(raw) = env::var("PLACEMENT_RANKING") else {
+            return Self::default();
+        };
+        let mut placements = HashMap::new();
+        for entry in raw.split(',').filter(|x| !x.trim().is_empty()) {
+            let Some((placement, ranking)) = entry.split_once('=') else {
+                tracing::warn!("ignore malformed placement ranking: {}", entry);
+                continue;
+            };
+            let ranking: Result<Ranking, serde::de::value::Error> =
+                Ranking::deserialize(ranking.trim().into_deserializer());
+            match ranking {
+                Ok(ranking) => {
+                    placements.insert(placement.trim().to_string(), ranking);
+                }
+                Err(err) => tracing::warn!("ignore placement ranking {}: {}", entry, err),
+            }
+        }
+        Self(placements)
+    }
+    pub fn get(&self, placement: &str) -> Option<Ranking> {
+        self.0.get(placement).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Item {
+        id: i32,
+        priority: i32,
+        weight: i32,
+    }
+
+    impl Ranked for Item {
+        fn priority(&self) -> i32 {
+            self.priority
+        }
+        fn weight(&self) -> i32 {
+            self.weight
+        }
+    }
+
+    fn item(id: i32, priority: i32, weight: i32) -> Item {
+        Item {
+            id,
+            priority,
+            weight,
+        }
+    }
+
+    #[test]
+    fn weighted_keeps_priority_tiers() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            let mut items = vec![item(1, 0, 100), item(2, 5, 1), item(3, 0, 1), item(4, 5, 1)];
+            weighted(&mut items, &mut rng);
+            let priorities: Vec<_> = items.iter().map(|x| x.priority).collect();
+            assert_eq!(priorities, vec![5, 5, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn weighted_first_place_follows_weight() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let rounds = 20000;
+        let mut first = HashMap::new();
+        for _ in 0..rounds {
+            let mut items = vec![item(1, 0, 1), item(2, 0, 3), item(3, 0, 6)];
+            weighted(&mut items, &mut rng);
+            *first.entry(items[0].id).or_insert(0) += 1;
+        }
+        for (id, share) in [(1, 0.1), (2, 0.3), (3, 0.6)] {
+            let observed = first[&id] as f64 / rounds as f64;
+            assert!(
+                (observed - share).abs() < 0.02,
+                "{id} first in {observed} of rounds, expected {share}"
+            );
+        }
+    }
+
+    #[test]
+    fn thompson_prefers_observed_clicks() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let trials = |x: &Item| match x.id {
+            1 => Trials {
+                impressions: 1000,
+                clicks: 10,
+            },
+            // more clicks than impressions count as a click on every impression
+            _ => Trials {
+                impressions: 1000,
+                clicks: 5000,
+            },
+        };
+        let mut first = 0;
+        for _ in 0..1000 {
+            let mut items = vec![item(1, 0, 1), item(2, 0, 1)];
+            thompson(&mut items, trials, &mut rng);
+            first += (items[0].id == 2) as i32;
+        }
+        assert_eq!(first, 1000);
+    }
+
+    #[test]
+    fn thompson_keeps_priority_tiers() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut items = vec![item(1, 0, 1), item(2, 5, 1)];
+        thompson(&mut items, |_| Trials::default(), &mut rng);
+        assert_eq!(items[0].id, 2);
+    }
+
+    #[test]
+    fn weighted_counts_missing_weight_as_one() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let rounds = 20000;
+        let mut first = 0;
+        for _ in 0..rounds {
+            let mut items = vec![item(1, 0, 0), item(2, 0, 1)];
+            weighted(&mut items, &mut rng);
+            first += (items[0].id == 1) as i32;
+        }
+        let observed = first as f64 / rounds as f64;
+        assert!(
+            (observed - 0.5).abs() < 0.02,
+            "first in {observed} of rounds"
+        );
+    }
+}
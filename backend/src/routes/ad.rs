@@ -1,40 +1,311 @@
+use crate::auction;
+use crate::delivery::{pacing, CounterStore};
+use crate::experiment::Split;
+use crate::ranking::{self, Ranked, Ranking, Trials};
+use crate::separation::{Separated, Separator};
+use crate::shared_cache::SharedCache;
+use crate::tracking::Kind;
 use crate::{database::*, routes::AppState};
 use axum::extract::Query;
+use axum::http::HeaderValue;
+use axum::response::{IntoResponse, Response};
 use axum::{extract::State, http::StatusCode, Json};
-use chrono::NaiveDateTime;
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use common::{Country, Gender, Platform};
 use moka::future::Cache;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::env;
 use std::future::Future;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio_postgres::error::SqlState;
 use tracing::instrument;
 
-pub struct ReadCache(Cache<Params, Vec<PartialAdvertisement>>);
+/// set on responses served from a cache entry past its soft TTL
+static STALE_HEADER: &str = "x-cache-stale";
+
+/// advertisement version before it is known, the shared tier is bypassed meanwhile
+const UNKNOWN_VERSION: i64 = -1;
+/// how long a replica loading a list into the shared tier keeps the others waiting for it
+const LOAD_CLAIM: Duration = Duration::from_secs(2);
+/// most time spent waiting on another replica, before loading the list anyway
+const CLAIM_WAIT: Duration = Duration::from_millis(500);
+const CLAIM_POLL: Duration = Duration::from_millis(25);
+
+/// full match list per [`Targeting`], pages are sliced from it
+///
+/// Keyed by the generation it was loaded in, so that a load started before a write
+/// cannot fill the cache of the next generation with stale ads.
+///
+/// An entry older than the soft TTL is still served, marked stale, while it is
+/// reloaded in the background; it is kept until the hard TTL if reloading fails. The
+/// latest entry of a targeting outlives a write the same way: when loading the new
+/// generation fails, the previous one is served stale until its hard TTL.
+///
+/// Misses go through the optional shared tier, keyed by the advertisement version
+/// so that a write invalidates the lists of every replica, before postgres.
+pub struct ReadCache {
+    cache: Cache<(u64, Targeting), Arc<Entry>>,
+    /// latest entry of every targeting whatever its generation, for when loading fails
+    fallback: Cache<Targeting, Arc<Entry>>,
+    generation: AtomicU64,
+    /// most advertisements kept per list, deeper pages are queried directly
+    list_limit: usize,
+    soft_ttl: Duration,
+    shared: Option<Arc<dyn SharedCache>>,
+    /// latest advertisement version known to this backend
+    version: AtomicI64,
+    /// requests per targeting since the last [`ReadCache::hottest`], at most `hit_keys`
+    hits: Mutex<HashMap<Targeting, u64>>,
+    hit_keys: usize,
+}
+
+struct Entry {
+    matches: Matches,
+    generation: u64,
+    refreshing: AtomicBool,
+}
 
 impl ReadCache {
-    pub fn new() -> Self {
-        Self(
-            Cache::builder()
-                .weigher(|_, val: &Vec<PartialAdvertisement>| val.len() as u32)
-                .time_to_live(Duration::new(60, 0))
+    /// `READ_CACHE_LIST_LIMIT`, 1000 when unset, `READ_CACHE_HIT_KEYS`, 65536 when unset,
+    /// and `READ_CACHE_SOFT_TTL` and `READ_CACHE_HARD_TTL` in seconds, 60 and 600 when unset
+    pub fn new(shared: Option<Arc<dyn SharedCache>>) -> Self {
+        let var = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(default)
+        };
+        let hard_ttl = Duration::from_secs(var("READ_CACHE_HARD_TTL", 600));
+        Self {
+            cache: Cache::builder()
+                .weigher(|_, val: &Arc<Entry>| val.matches.ads.len().max(1) as u32)
+                .time_to_live(hard_ttl)
                 .max_capacity(131072)
                 .build(),
-        )
+            // holds the same entries as `cache`, and those of past generations
+            fallback: Cache::builder()
+                .weigher(|_, val: &Arc<Entry>| val.matches.ads.len().max(1) as u32)
+                .time_to_live(hard_ttl)
+                .max_capacity(131072)
+                .build(),
+            generation: AtomicU64::new(0),
+            list_limit: var("READ_CACHE_LIST_LIMIT", 1000) as usize,
+            soft_ttl: Duration::from_secs(var("READ_CACHE_SOFT_TTL", 60)),
+            shared,
+            version: AtomicI64::new(UNKNOWN_VERSION),
+            hits: Mutex::default(),
+            hit_keys: var("READ_CACHE_HIT_KEYS", 65536) as usize,
+        }
     }
     async fn get_or_insert_async<E, F, Fut>(
         &self,
-        key: Params,
+        key: Targeting,
         f: F,
-    ) -> Result<Vec<PartialAdvertisement>, Arc<E>>
+    ) -> Result<Arc<Entry>, Arc<E>>
     where
-        F: FnOnce(Params) -> Fut,
-        Fut: Future<Output = Result<Vec<PartialAdvertisement>, E>>,
+        F: FnOnce(Targeting) -> Fut,
+        Fut: Future<Output = Result<Matches, E>>,
         E: Send + Sync + 'static,
     {
-        self.0.try_get_with(key.clone(), f(key)).await
+        let generation = self.generation.load(Ordering::Acquire);
+        let load = f(key.clone());
+        let loaded = self
+            .cache
+            .try_get_with((generation, key.clone()), async {
+                let entry = Arc::new(Entry {
+                    matches: load.await?,
+                    generation,
+                    refreshing: AtomicBool::new(false),
+                });
+                if !self.is_outdated(&entry) {
+                    self.fallback.insert(key.clone(), entry.clone()).await;
+                }
+                Ok(entry)
+            })
+            .await;
+        match loaded {
+            Err(err) => match self.fallback.get(&key).await {
+                Some(entry) => {
+                    tracing::warn!("failed to load advertisements, serving a past generation");
+                    Ok(entry)
+                }
+                None => Err(err),
+            },
+            x => x,
+        }
+    }
+    /// count a request, when a new targeting would exceed `hit_keys` every count is halved
+    /// and those dropping to zero forgotten, until half the room is free again
+    ///
+    /// Scanning many distinct targetings cannot grow the map, a pass runs at most once per
+    /// `hit_keys / 2` new targetings and forgets the least requested first.
+    fn hit(&self, targeting: &Targeting) {
+        let mut hits = self.hits.lock().unwrap();
+        match hits.get_mut(targeting) {
+            Some(count) => *count += 1,
+            None => {
+                if hits.len() >= self.hit_keys {
+                    while hits.len() > self.hit_keys / 2 {
+                        hits.retain(|_, count| {
+                            *count /= 2;
+                            *count > 0
+                        });
+                    }
+                }
+                hits.insert(targeting.clone(), 1);
+            }
+        }
+    }
+    /// the `n` most requested targetings with their requests since the last call
+    pub fn hottest(&self, n: usize) -> Vec<(Targeting, u64)> {
+        let mut hits: Vec<_> = std::mem::take(&mut *self.hits.lock().unwrap())
+            .into_iter()
+            .collect();
+        hits.sort_by_key(|(_, count)| Reverse(*count));
+        hits.truncate(n);
+        hits
     }
+    /// past the soft TTL, or of a past generation
+    fn is_stale(&self, entry: &Entry) -> bool {
+        entry.matches.loaded_at.elapsed() >= self.soft_ttl || self.is_outdated(entry)
+    }
+    fn is_outdated(&self, entry: &Entry) -> bool {
+        entry.generation != self.generation.load(Ordering::Acquire)
+    }
+    /// whether the caller should reload a stale entry, only one caller is chosen at a time
+    ///
+    /// An entry of a past generation is not refreshed, the next lookup loads the current one.
+    fn start_refresh(&self, entry: &Entry) -> bool {
+        self.is_stale(entry)
+            && !self.is_outdated(entry)
+            && !entry.refreshing.swap(true, Ordering::AcqRel)
+    }
+    async fn finish_refresh(
+        &self,
+        key: Targeting,
+        entry: &Entry,
+        matches: Result<Matches, tokio_postgres::Error>,
+    ) {
+        match matches {
+            Ok(matches) => {
+                let fresh = Arc::new(Entry {
+                    matches,
+                    generation: entry.generation,
+                    refreshing: AtomicBool::new(false),
+                });
+                self.cache
+                    .insert((entry.generation, key.clone()), fresh.clone())
+                    .await;
+                // unless a write started a generation meanwhile
+                if !self.is_outdated(&fresh) {
+                    self.fallback.insert(key, fresh).await;
+                }
+            }
+            Err(err) => {
+                tracing::warn!("failed to refresh advertisements, serving stale: {:?}", err);
+                entry.refreshing.store(false, Ordering::Release);
+            }
+        }
+    }
+    /// start a new generation, entries loaded before are only served when loading fails
+    ///
+    /// `version` covers the changes that caused it, the shared tier is bypassed until a
+    /// version is known again when it is `None`.
+    pub fn invalidate(&self, version: Option<i64>) {
+        match version {
+            Some(version) => self.version.fetch_max(version, Ordering::AcqRel),
+            None => self.version.swap(UNKNOWN_VERSION, Ordering::AcqRel),
+        };
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        self.cache.invalidate_all();
+    }
+    /// load the list of `targeting` through the shared tier, which one replica at a time fills
+    async fn load_shared<F, Fut>(
+        &self,
+        targeting: &Targeting,
+        load: F,
+    ) -> Result<Stored, tokio_postgres::Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Stored, tokio_postgres::Error>>,
+    {
+        let version = self.version.load(Ordering::Acquire);
+        let shared = match &self.shared {
+            Some(shared) if version != UNKNOWN_VERSION => shared.as_ref(),
+            _ => return load().await,
+        };
+        let key = format!(
+            "ad:{}:{}",
+            version,
+            serde_json::to_string(targeting).expect("targeting is serializable")
+        );
+        if let Some(stored) = Self::get_shared(shared, &key).await {
+            return Ok(stored);
+        }
+        let claim = format!("{key}:loading");
+        let claimed = shared.add(&claim, LOAD_CLAIM).await;
+        if !claimed {
+            let waited = Instant::now();
+            while waited.elapsed() < CLAIM_WAIT {
+                tokio::time::sleep(CLAIM_POLL).await;
+                if let Some(stored) = Self::get_shared(shared, &key).await {
+                    return Ok(stored);
+                }
+            }
+        }
+        let stored = load().await;
+        if let Ok(stored) = &stored {
+            // kept no longer than the soft TTL, so that a stale list is reloaded from postgres
+            match serde_json::to_vec(stored) {
+                Ok(value) => shared.set(&key, value, self.soft_ttl).await,
+                Err(err) => tracing::warn!("failed to serialize advertisements: {:?}", err),
+            }
+        }
+        if claimed {
+            shared.remove(&claim).await;
+        }
+        stored
+    }
+    async fn get_shared(shared: &dyn SharedCache, key: &str) -> Option<Stored> {
+        let value = shared.get(key).await?;
+        serde_json::from_slice(&value)
+            .inspect_err(|err| tracing::warn!("ignore malformed shared list {}: {:?}", key, err))
+            .ok()
+    }
+}
+
+/// normalized targeting of a request, independent of paging and ranking
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
+pub struct Targeting {
+    age: Option<i32>,
+    #[serde(with = "common::alpha2")]
+    country: Option<Country>,
+    platform: Option<Platform>,
+    gender: Option<Gender>,
+    sort: Sort,
+    /// house advertisements, matched by country and platform only
+    house: bool,
+}
+
+/// advertisements matching a [`Targeting`], in its sort order
+pub struct Matches {
+    ads: Vec<PartialAdvertisement>,
+    /// every match fits in `ads`
+    complete: bool,
+    loaded_at: Instant,
+}
+
+/// a [`Matches`] list as stored in the shared tier, before it is priced by a backend
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    ads: Vec<crate::database::PartialAdvertisement>,
+    complete: bool,
+    loaded_at: DateTime<Utc>,
 }
 
 fn default_limit() -> usize {
@@ -44,6 +315,7 @@ fn default_limit() -> usize {
 #[derive(serde::Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Params {
+    /// servable advertisements to skip, counted after experiments, pacing and separation
     #[serde(default)]
     offset: usize,
     #[serde(default = "default_limit")]
@@ -56,55 +328,206 @@ pub struct Params {
     platform: Option<Platform>,
     #[serde(default)]
     gender: Option<Gender>,
+    #[serde(default)]
+    sort: Sort,
+    /// continue after the page that returned this cursor, `offset` is then relative to it
+    #[serde(default)]
+    cursor: Option<Cursor>,
+    #[serde(default)]
+    ranking: Option<Ranking>,
+    #[serde(default)]
+    placement: Option<String>,
+    /// minimum price per thousand impressions in micros, under [`Ranking::Auction`]
+    #[serde(default)]
+    floor: Option<i64>,
+    /// assigns the user to the same experiment variants on every request
+    #[serde(default)]
+    user_id: Option<String>,
+}
+
+/// opaque pagination token, the sort and keyset position of the last served item
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct Cursor {
+    sort: Sort,
+    after: After,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let raw = format!(
+            "{}:{}:{}:{}",
+            self.sort as usize,
+            self.after.priority,
+            self.after.end_at.and_utc().timestamp_micros(),
+            self.after.id
+        );
+        BASE64_URL_SAFE_NO_PAD.encode(raw)
+    }
+    fn decode(token: &str) -> Option<Self> {
+        let raw = String::from_utf8(BASE64_URL_SAFE_NO_PAD.decode(token).ok()?).ok()?;
+        let mut fields = raw.split(':');
+        let sort = *Sort::ALL.get(fields.next()?.parse::<usize>().ok()?)?;
+        let priority = fields.next()?.parse().ok()?;
+        let end_at = DateTime::from_timestamp_micros(fields.next()?.parse().ok()?)?.naive_utc();
+        let id = fields.next()?.parse().ok()?;
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            sort,
+            after: After {
+                priority,
+                end_at,
+                id,
+            },
+        })
+    }
+}
+
+impl<'a> Deserialize<'a> for Cursor {
+    fn deserialize<D>(deserializer: D) -> Result<Cursor, D::Error>
+    where
+        D: serde::Deserializer<'a>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Cursor::decode(&s).ok_or_else(|| serde::de::Error::custom("invalid cursor"))
+    }
+}
+
+impl Params {
+    /// a cursor only resumes a page of the same sort
+    fn cursor_fits_sort(&self) -> bool {
+        self.cursor.is_none_or(|x| x.sort == self.sort)
+    }
+    fn targeting(&self) -> Targeting {
+        Targeting {
+            age: self.age,
+            country: self.country.clone(),
+            platform: self.platform,
+            gender: self.gender.clone(),
+            sort: self.sort,
+            house: false,
+        }
+    }
+    fn house_targeting(&self) -> Targeting {
+        Targeting {
+            age: None,
+            country: self.country.clone(),
+            platform: self.platform,
+            gender: None,
+            sort: Sort::PriorityDesc,
+            house: true,
+        }
+    }
 }
 
 #[derive(Serialize, Clone)]
 pub struct PartialAdvertisement {
+    id: i32,
     title: String,
     end_at: NaiveDateTime,
+    /// set when served as the variant of an experiment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    experiment_id: Option<i32>,
+    /// unpaid fallback, not to be counted as a paid impression
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    house: bool,
+    /// pass to `POST /track/impression` once the advertisement is shown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    impression_token: Option<String>,
+    /// pass to `GET /track/click` when the advertisement is clicked, it redirects to the landing URL
+    #[serde(skip_serializing_if = "Option::is_none")]
+    click_token: Option<String>,
+    #[serde(skip)]
+    priority: i32,
+    #[serde(skip)]
+    weight: i32,
+    #[serde(skip)]
+    bid: i64,
+    #[serde(skip)]
+    bid_type: BidType,
+    /// expected price per thousand impressions
+    #[serde(skip)]
+    ecpm: i64,
+    /// price charged for this impression, per thousand
+    #[serde(skip)]
+    price: i64,
+    #[serde(skip)]
+    budget: Budget,
+    #[serde(skip)]
+    advertiser: Option<String>,
+    #[serde(skip)]
+    category: Option<String>,
+}
+
+impl Ranked for PartialAdvertisement {
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+    fn weight(&self) -> i32 {
+        self.weight
+    }
+}
+
+impl Separated for PartialAdvertisement {
+    fn advertiser(&self) -> Option<&str> {
+        self.advertiser.as_deref()
+    }
+    fn category(&self) -> Option<&str> {
+        self.category.as_deref()
+    }
 }
 #[derive(Serialize, Default, Clone)]
 pub struct PartialAdvertisements {
     items: Vec<PartialAdvertisement>,
+    /// set when the page is full, pass as `cursor` to get the next page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
 }
 
 #[instrument(name = "GET /ad", skip(state, params))]
 pub async fn handler(
     State(state): State<Arc<AppState>>,
     Query(params): Query<Params>,
-) -> Result<Json<PartialAdvertisements>, StatusCode> {
+) -> Result<Response, StatusCode> {
     if params.limit == 0 {
-        return Ok(Json(PartialAdvertisements::default()));
+        return Ok(Json(PartialAdvertisements::default()).into_response());
+    }
+    if !params.cursor_fits_sort() {
+        return Err(StatusCode::BAD_REQUEST);
     }
+    let pacer = Pacer::new(state.delivery.as_ref());
 
-    let client = &state.client;
-    let items: Result<_, Arc<tokio_postgres::Error>> = state
-        .read_cache
-        .get_or_insert_async(params, move |params| async move {
-            let ads = client
-                .query_partial(
-                    Condition {
-                        age: params.age,
-                        country: params.country,
-                        platform: params.platform,
-                        gender: params.gender,
-                    },
-                    (params.limit, params.offset),
-                )
-                .await?;
-
-            Ok(ads
-                .into_iter()
-                .map(|x| PartialAdvertisement {
-                    title: x.title,
-                    end_at: x.end_at,
-                })
-                .collect())
+    let ranking = params
+        .ranking
+        .or_else(|| {
+            let placement = params.placement.as_deref()?;
+            state.placements.get(placement)
         })
-        .await;
+        .unwrap_or_default();
 
-    let items = match items {
-        Ok(ads) => ads,
+    // without a user id, variants are assigned for this request only
+    let subject = params
+        .user_id
+        .clone()
+        .unwrap_or_else(|| format!("anonymous:{}", rand::random::<u64>()));
+    let admit = |x: PartialAdvertisement| {
+        let x = match state.experiments.split(x.id, &subject) {
+            Split::Unsplit => x,
+            Split::Variant(experiment_id) => PartialAdvertisement {
+                experiment_id: Some(experiment_id),
+                ..x
+            },
+            Split::Excluded => return None,
+        };
+        pacer.allow(&x).then_some(x)
+    };
+    let page = match ranking {
+        Ranking::Priority => priority_page(&state, &params, admit).await,
+        _ => pooled_page(&state, &params, ranking, admit).await,
+    };
+    let page = match page {
+        Ok(x) => x,
         Err(err) => {
             tracing::error!("failed to query partial advertisements: {:?}", err);
             let code = SqlState::from_code("26000");
@@ -115,5 +538,761 @@ pub async fn handler(
         }
     };
 
-    Ok(Json(PartialAdvertisements { items }))
+    // house advertisements fill the first page only when no paid advertisement matches;
+    // paid ones held back by experiments, pacing or separation leave the page short instead
+    if !page.matched && params.offset == 0 && params.cursor.is_none() {
+        // the paid query succeeded, so a failing fallback still answers with the blank page
+        let house = fetch(&state, params.house_targeting(), None, (params.limit, 0));
+        let (house, stale) = house.await.unwrap_or_else(|err| {
+            tracing::warn!("failed to query house advertisements: {:?}", err);
+            (Vec::new(), false)
+        });
+        let page = PartialAdvertisements {
+            items: sign(&state, &params, house),
+            next_cursor: None,
+        };
+        return Ok(respond(page, stale));
+    }
+
+    let items = sign(&state, &params, page.items);
+    let next_cursor = page.next_cursor;
+    Ok(respond(
+        PartialAdvertisements { items, next_cursor },
+        page.stale,
+    ))
+}
+
+/// one page of a request, before it is signed
+#[derive(Default)]
+struct Page {
+    items: Vec<PartialAdvertisement>,
+    next_cursor: Option<String>,
+    /// whether any paid advertisement matched the targeting, served or not
+    matched: bool,
+    stale: bool,
+}
+
+async fn priority_page(
+    state: &Arc<AppState>,
+    params: &Params,
+    admit: impl Fn(PartialAdvertisement) -> Option<PartialAdvertisement>,
+) -> Result<Page, Arc<tokio_postgres::Error>> {
+    let targeting = params.targeting();
+    let fetch = |after, limit| fetch(state, targeting.clone(), after, (limit, 0));
+    walk(params, state.separation.separator(), fetch, admit).await
+}
+
+/// walk the match list from the cursor until `limit` advertisements are admitted and
+/// separated, `offset` skips as many of those first
+///
+/// The next cursor is the position of the last advertisement considered, so that the
+/// following page resumes after it whether it was served or held back.
+async fn walk<F>(
+    params: &Params,
+    mut separator: Separator<'_>,
+    fetch: impl Fn(Option<After>, usize) -> F,
+    admit: impl Fn(PartialAdvertisement) -> Option<PartialAdvertisement>,
+) -> Result<Page, Arc<tokio_postgres::Error>>
+where
+    F: Future<Output = Result<(Vec<PartialAdvertisement>, bool), Arc<tokio_postgres::Error>>>,
+{
+    let mut after = params.cursor.map(|x| x.after);
+    let mut batch = params.offset + params.limit;
+    let mut skipped = 0;
+    let mut page = Page::default();
+    loop {
+        let (fetched, stale) = fetch(after, batch).await?;
+        page.stale |= stale;
+        page.matched |= !fetched.is_empty();
+        let exhausted = fetched.len() < batch;
+        for x in fetched {
+            after = Some(x.position());
+            let Some(x) = admit(x).filter(|x| separator.admit(x)) else {
+                continue;
+            };
+            if skipped < params.offset {
+                skipped += 1;
+                continue;
+            }
+            page.items.push(x.at_ecpm());
+            if page.items.len() == params.limit {
+                page.next_cursor = after.map(|after| {
+                    Cursor {
+                        sort: params.sort,
+                        after,
+                    }
+                    .encode()
+                });
+                return Ok(page);
+            }
+        }
+        if exhausted {
+            return Ok(page);
+        }
+        // held back advertisements are skipped in ever larger batches
+        batch *= 2;
+    }
+}
+
+/// rank a pool larger than the page and slice the page from it, the pool is drawn anew on
+/// every request so there is no position to resume from
+async fn pooled_page(
+    state: &Arc<AppState>,
+    params: &Params,
+    ranking: Ranking,
+    admit: impl Fn(PartialAdvertisement) -> Option<PartialAdvertisement>,
+) -> Result<Page, Arc<tokio_postgres::Error>> {
+    let pool_size = state.ranking_pool.max(params.offset + params.limit);
+    let (fetched, stale) = fetch(state, params.targeting(), None, (pool_size, 0)).await?;
+    let matched = !fetched.is_empty();
+    let mut pool: Vec<_> = fetched.into_iter().filter_map(admit).collect();
+    match ranking {
+        Ranking::Priority => {}
+        Ranking::Weighted => ranking::weighted(&mut pool, &mut rand::thread_rng()),
+        Ranking::Bandit => ranking::thompson(
+            &mut pool,
+            |x| {
+                let usage = state.delivery.usage(x.id);
+                Trials {
+                    impressions: usage.impressions,
+                    clicks: usage.clicks,
+                }
+            },
+            &mut rand::thread_rng(),
+        ),
+        Ranking::Auction => pool.sort_by_key(|x| Reverse(x.ecpm)),
+    }
+    // separated before slicing, so that its pages neither repeat nor skip items
+    let pool = state.separation.apply(pool);
+
+    let items = match ranking {
+        Ranking::Auction => auction::clear(
+            pool.into_iter()
+                .map(|x| {
+                    let ecpm = x.ecpm;
+                    (x, ecpm)
+                })
+                .collect(),
+            params.offset + params.limit,
+            params.floor.unwrap_or_default(),
+            state.auction_rule,
+        )
+        .into_iter()
+        .skip(params.offset)
+        .map(|won| PartialAdvertisement {
+            price: won.price,
+            ..won.item
+        })
+        .collect(),
+        _ => pool
+            .into_iter()
+            .skip(params.offset)
+            .take(params.limit)
+            .map(PartialAdvertisement::at_ecpm)
+            .collect(),
+    };
+    Ok(Page {
+        items,
+        next_cursor: None,
+        matched,
+        stale,
+    })
+}
+
+/// attach the tracking tokens of each item, delivery is counted once it is tracked
+///
+/// A CPM bid is charged per tracked impression, a CPC bid per tracked click instead, at
+/// its clearing price converted back to a price per click.
+fn sign(
+    state: &AppState,
+    params: &Params,
+    items: Vec<PartialAdvertisement>,
+) -> Vec<PartialAdvertisement> {
+    let request_id = format!("{:016x}", rand::random::<u64>());
+    let served_at = Utc::now().naive_utc();
+    items
+        .into_iter()
+        .map(|x| {
+            let (cost, click_cost) = match x.bid_type {
+                _ if x.house => (0, 0),
+                BidType::Cpm => (pacing::cost(x.price), 0),
+                BidType::Cpc => (0, auction::cpc(x.price, x.bid, state.default_ctr)),
+            };
+            let impression = Impression {
+                advertisement_id: x.id,
+                request_id: request_id.clone(),
+                experiment_id: x.experiment_id,
+                user_id: params.user_id.clone(),
+                cost,
+                click_cost,
+                house: x.house,
+                age: params.age,
+                country: params.country.clone(),
+                platform: params.platform,
+                gender: params.gender.clone(),
+                placement: params.placement.clone(),
+                served_at,
+                invalid: None,
+            };
+            PartialAdvertisement {
+                impression_token: Some(state.signer.sign(Kind::Impression, &impression)),
+                click_token: Some(state.signer.sign(Kind::Click, &impression)),
+                ..x
+            }
+        })
+        .collect()
+}
+
+fn respond(page: PartialAdvertisements, stale: bool) -> Response {
+    let mut response = Json(page).into_response();
+    if stale {
+        response
+            .headers_mut()
+            .insert(STALE_HEADER, HeaderValue::from_static("true"));
+    }
+    response
+}
+
+/// holds back advertisements that are out of budget or ahead of their daily pace
+struct Pacer<'a> {
+    store: &'a dyn CounterStore,
+    now: NaiveDateTime,
+}
+
+impl<'a> Pacer<'a> {
+    fn new(store: &'a dyn CounterStore) -> Self {
+        Self {
+            store,
+            now: Utc::now().naive_utc(),
+        }
+    }
+    fn allow(&self, ad: &PartialAdvertisement) -> bool {
+        pacing::allow(
+            &ad.budget,
+            &self.store.usage(ad.id),
+            pacing::cost(ad.ecpm),
+            self.now,
+            &mut rand::thread_rng(),
+        )
+    }
+}
+
+impl PartialAdvertisement {
+    fn position(&self) -> After {
+        After {
+            priority: self.priority,
+            end_at: self.end_at,
+            id: self.id,
+        }
+    }
+    /// charge the expected price, for rankings without an auction
+    fn at_ecpm(self) -> Self {
+        Self {
+            price: self.ecpm,
+            ..self
+        }
+    }
+}
+
+/// `limit` advertisements matching `targeting` from `offset`, after the position `after`,
+/// and whether they are served stale from the cache
+async fn fetch(
+    state: &Arc<AppState>,
+    targeting: Targeting,
+    after: Option<After>,
+    (limit, offset): (usize, usize),
+) -> Result<(Vec<PartialAdvertisement>, bool), Arc<tokio_postgres::Error>> {
+    state.read_cache.hit(&targeting);
+    let entry = state
+        .read_cache
+        .get_or_insert_async(targeting.clone(), |targeting| async move {
+            load_matches(state, &targeting).await
+        })
+        .await?;
+    let stale = state.read_cache.is_stale(&entry);
+    if state.read_cache.start_refresh(&entry) {
+        let (state, targeting, entry) = (state.clone(), targeting.clone(), entry.clone());
+        tokio::spawn(async move {
+            let matches = load_matches(&state, &targeting).await;
+            state
+                .read_cache
+                .finish_refresh(targeting, &entry, matches)
+                .await;
+        });
+    }
+
+    let matches = &entry.matches;
+    let start = after.map_or(0, |after| {
+        matches
+            .ads
+            .partition_point(|x| targeting.sort.compare(&x.position(), &after).is_le())
+    }) + offset;
+    if matches.complete || start + limit <= matches.ads.len() {
+        let page = matches
+            .ads
+            .iter()
+            .skip(start)
+            .take(limit)
+            .cloned()
+            .collect();
+        return Ok((page, stale));
+    }
+    // deeper than the cached list
+    let page = load(state, &targeting, after, (limit, offset)).await;
+    Ok((page.map_err(Arc::new)?, false))
+}
+
+/// load the list of `targeting` into the read cache, unless it is there
+pub async fn preload(
+    state: &Arc<AppState>,
+    targeting: Targeting,
+) -> Result<(), Arc<tokio_postgres::Error>> {
+    state
+        .read_cache
+        .get_or_insert_async(targeting, |targeting| async move {
+            load_matches(state, &targeting).await
+        })
+        .await
+        .map(|_| ())
+}
+
+async fn load_matches(
+    state: &AppState,
+    targeting: &Targeting,
+) -> Result<Matches, tokio_postgres::Error> {
+    let list_limit = state.read_cache.list_limit;
+    let stored = state
+        .read_cache
+        .load_shared(targeting, || async {
+            let mut ads = query(state, targeting, None, (list_limit + 1, 0)).await?;
+            let complete = ads.len() <= list_limit;
+            ads.truncate(list_limit);
+            Ok(Stored {
+                ads,
+                complete,
+                loaded_at: Utc::now(),
+            })
+        })
+        .await?;
+
+    // a list loaded by another replica is as old as that load
+    let age = (Utc::now() - stored.loaded_at).to_std().unwrap_or_default();
+    Ok(Matches {
+        ads: serve(state, targeting, stored.ads),
+        complete: stored.complete,
+        loaded_at: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+    })
+}
+
+async fn load(
+    state: &AppState,
+    targeting: &Targeting,
+    after: Option<After>,
+    (limit, offset): (usize, usize),
+) -> Result<Vec<PartialAdvertisement>, tokio_postgres::Error> {
+    let ads = query(state, targeting, after, (limit, offset)).await?;
+    Ok(serve(state, targeting, ads))
+}
+
+async fn query(
+    state: &AppState,
+    targeting: &Targeting,
+    after: Option<After>,
+    (limit, offset): (usize, usize),
+) -> Result<Vec<crate::database::PartialAdvertisement>, tokio_postgres::Error> {
+    let cond = Condition {
+        age: targeting.age,
+        country: targeting.country.clone(),
+        platform: targeting.platform,
+        gender: targeting.gender.clone(),
+    };
+    let ads = match (&state.index, targeting.house) {
+        (Some(index), true) => index.query_house(cond.country, cond.platform, limit),
+        (Some(index), false) => index.query_partial(cond, targeting.sort, after, (limit, offset)),
+        (None, true) => {
+            state
+                .client
+                .query_house(cond.country, cond.platform, limit)
+                .await?
+        }
+        (None, false) => {
+            state
+                .client
+                .query_partial(cond, targeting.sort, after, (limit, offset))
+                .await?
+        }
+    };
+    Ok(ads)
+}
+
+fn serve(
+    state: &AppState,
+    targeting: &Targeting,
+    ads: Vec<crate::database::PartialAdvertisement>,
+) -> Vec<PartialAdvertisement> {
+    ads.into_iter()
+        .map(|x| PartialAdvertisement {
+            id: x.id,
+            title: x.title,
+            end_at: x.end_at,
+            experiment_id: None,
+            house: targeting.house,
+            impression_token: None,
+            click_token: None,
+            priority: x.priority,
+            weight: x.weight,
+            bid: x.bid,
+            bid_type: x.bid_type,
+            ecpm: auction::ecpm(x.bid, x.bid_type, state.default_ctr),
+            price: 0,
+            budget: x.budget,
+            advertiser: x.advertiser,
+            category: x.category,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared_cache::tests::Memory;
+
+    fn targeting() -> Targeting {
+        Targeting {
+            age: Some(30),
+            country: None,
+            platform: None,
+            gender: None,
+            sort: Sort::default(),
+            house: false,
+        }
+    }
+
+    fn matches(ids: &[i32]) -> Matches {
+        let ads = ids
+            .iter()
+            .map(|&id| PartialAdvertisement {
+                id,
+                title: format!("ad {id}"),
+                end_at: NaiveDateTime::default(),
+                experiment_id: None,
+                house: false,
+                impression_token: None,
+                click_token: None,
+                priority: 0,
+                weight: 1,
+                bid: 0,
+                bid_type: BidType::default(),
+                ecpm: 0,
+                price: 0,
+                budget: Budget::default(),
+                advertiser: None,
+                category: None,
+            })
+            .collect();
+        Matches {
+            ads,
+            complete: true,
+            loaded_at: Instant::now(),
+        }
+    }
+
+    /// the ids of the list cached for `targeting()`, loading `ids` on a miss
+    async fn lookup(cache: &ReadCache, ids: &[i32]) -> Result<Vec<i32>, Arc<()>> {
+        let loaded = matches(ids);
+        let entry = cache
+            .get_or_insert_async(targeting(), |_| async { Ok(loaded) })
+            .await?;
+        Ok(entry.matches.ads.iter().map(|x| x.id).collect())
+    }
+
+    #[tokio::test]
+    async fn invalidate_misses_warm_entries() {
+        let cache = ReadCache::new(None);
+        assert_eq!(lookup(&cache, &[1]).await, Ok(vec![1]));
+        // warm, the list is not loaded again
+        assert_eq!(lookup(&cache, &[2]).await, Ok(vec![1]));
+
+        // as applied after a write to the advertisements
+        cache.invalidate(Some(1));
+        assert_eq!(lookup(&cache, &[2]).await, Ok(vec![2]));
+        assert_eq!(lookup(&cache, &[3]).await, Ok(vec![2]));
+    }
+
+    #[test]
+    fn hits_are_bounded_and_keep_the_hottest() {
+        let mut cache = ReadCache::new(None);
+        cache.hit_keys = 16;
+        let hot = targeting();
+        let warm = Targeting {
+            age: Some(40),
+            ..targeting()
+        };
+        // a scan over many targetings requested once each, between steady requests
+        for age in 100..1100 {
+            cache.hit(&hot);
+            if age % 2 == 0 {
+                cache.hit(&warm);
+            }
+            cache.hit(&Targeting {
+                age: Some(age),
+                ..targeting()
+            });
+            assert!(cache.hits.lock().unwrap().len() <= 16);
+        }
+        let hottest = cache.hottest(2);
+        assert_eq!(hottest[0].0, hot);
+        assert_eq!(hottest[1].0, warm);
+        assert!(hottest[0].1 > hottest[1].1);
+        // counted from zero again
+        assert_eq!(cache.hottest(2), vec![]);
+    }
+
+    #[tokio::test]
+    async fn load_racing_a_write_stays_in_its_generation() {
+        let cache = ReadCache::new(None);
+        let (loaded, load) = tokio::sync::oneshot::channel::<()>();
+        let slow = async {
+            let entry = cache
+                .get_or_insert_async(targeting(), |_| async {
+                    let _ = load.await;
+                    Ok::<_, ()>(matches(&[1]))
+                })
+                .await;
+            entry.map(|x| x.generation)
+        };
+        let write = async {
+            cache.invalidate(Some(1));
+            let _ = loaded.send(());
+        };
+        let (generation, ()) = tokio::join!(slow, write);
+        assert_eq!(generation, Ok(0));
+        assert_eq!(lookup(&cache, &[2]).await, Ok(vec![2]));
+    }
+
+    async fn failing_lookup(cache: &ReadCache) -> Result<(Vec<i32>, bool), Arc<()>> {
+        let entry = cache
+            .get_or_insert_async(targeting(), |_| async { Err(()) })
+            .await?;
+        let ids = entry.matches.ads.iter().map(|x| x.id).collect();
+        Ok((ids, cache.is_stale(&entry)))
+    }
+
+    #[tokio::test]
+    async fn past_generation_is_served_stale_when_loading_fails() {
+        let cache = ReadCache::new(None);
+        assert_eq!(failing_lookup(&cache).await, Err(Arc::new(())));
+        assert_eq!(lookup(&cache, &[1]).await, Ok(vec![1]));
+
+        cache.invalidate(Some(1));
+        assert_eq!(failing_lookup(&cache).await, Ok((vec![1], true)));
+        // not refreshed in the background, the next lookup loads the current generation
+        let entry = cache.fallback.get(&targeting()).await.unwrap();
+        assert!(!cache.start_refresh(&entry));
+        assert_eq!(lookup(&cache, &[2]).await, Ok(vec![2]));
+        assert_eq!(failing_lookup(&cache).await, Ok((vec![2], false)));
+    }
+
+    fn stored(ids: &[i32]) -> Stored {
+        let ads = ids
+            .iter()
+            .map(|&id| crate::database::PartialAdvertisement {
+                id,
+                title: format!("ad {id}"),
+                end_at: NaiveDateTime::default(),
+                priority: 0,
+                weight: 1,
+                bid: 1000,
+                budget: Budget::default(),
+                bid_type: BidType::Cpc,
+                advertiser: Some("acme".to_string()),
+                category: None,
+            })
+            .collect();
+        Stored {
+            ads,
+            complete: true,
+            loaded_at: Utc::now(),
+        }
+    }
+
+    /// a backend that knows the advertisement version, so that it uses the shared tier
+    fn replica(shared: &Arc<Memory>) -> ReadCache {
+        let cache = ReadCache::new(Some(shared.clone()));
+        cache.invalidate(Some(1));
+        cache
+    }
+
+    #[tokio::test]
+    async fn shared_tier_round_trips_lists() {
+        let shared = Arc::new(Memory::default());
+        let stored = stored(&[1, 2]);
+        let value = serde_json::to_value(&stored).unwrap();
+        let loaded = replica(&shared)
+            .load_shared(&targeting(), || async { Ok(stored) })
+            .await
+            .unwrap();
+        assert_eq!(serde_json::to_value(&loaded).unwrap(), value);
+
+        // another replica reads the list instead of postgres
+        let postgres = AtomicBool::new(false);
+        let read = replica(&shared)
+            .load_shared(&targeting(), || async {
+                postgres.store(true, Ordering::Release);
+                Ok(self::stored(&[3]))
+            })
+            .await
+            .unwrap();
+        assert!(!postgres.load(Ordering::Acquire));
+        assert_eq!(serde_json::to_value(&read).unwrap(), value);
+    }
+
+    #[tokio::test]
+    async fn waiting_replica_picks_up_the_filled_list() {
+        let shared = Arc::new(Memory::default());
+        let (loading, waiting) = (replica(&shared), replica(&shared));
+        let postgres = AtomicBool::new(false);
+        let targeting = targeting();
+        let first = loading.load_shared(&targeting, || async {
+            tokio::time::sleep(CLAIM_WAIT / 5).await;
+            Ok(stored(&[1]))
+        });
+        let second = async {
+            // once the first replica holds the claim
+            tokio::time::sleep(CLAIM_POLL).await;
+            waiting
+                .load_shared(&targeting, || async {
+                    postgres.store(true, Ordering::Release);
+                    Ok(stored(&[2]))
+                })
+                .await
+        };
+        let (first, second) = tokio::join!(first, second);
+        assert!(!postgres.load(Ordering::Acquire));
+        assert_eq!(first.unwrap().ads[0].id, 1);
+        assert_eq!(second.unwrap().ads[0].id, 1);
+    }
+
+    #[tokio::test]
+    async fn invalidate_without_version_bypasses_shared_tier() {
+        let cache = ReadCache::new(None);
+        cache.invalidate(Some(7));
+        assert_eq!(cache.version.load(Ordering::Acquire), 7);
+        // a late notification of an older change keeps the latest version
+        cache.invalidate(Some(5));
+        assert_eq!(cache.version.load(Ordering::Acquire), 7);
+        cache.invalidate(None);
+        assert_eq!(cache.version.load(Ordering::Acquire), UNKNOWN_VERSION);
+    }
+
+    fn params(query: &str) -> Result<Params, String> {
+        let uri: axum::http::Uri = format!("/ad?{query}").parse().unwrap();
+        Query::<Params>::try_from_uri(&uri)
+            .map(|x| x.0)
+            .map_err(|x| x.body_text())
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = Cursor {
+            sort: Sort::EndAtDesc,
+            after: After {
+                priority: -3,
+                end_at: DateTime::from_timestamp_micros(1_700_000_000_123_456)
+                    .unwrap()
+                    .naive_utc(),
+                id: 42,
+            },
+        };
+        assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+        let params = params(&format!("sort=-end_at&cursor={}", cursor.encode())).unwrap();
+        assert_eq!(params.cursor, Some(cursor));
+    }
+
+    #[test]
+    fn malformed_cursors_are_rejected() {
+        let encode = |raw: &str| BASE64_URL_SAFE_NO_PAD.encode(raw);
+        for token in [
+            String::new(),
+            "not base64!".to_string(),
+            encode("0:1:2"),
+            encode("0:1:2:3:4"),
+            // no such sort
+            encode("9:1:2:3"),
+            encode("0:x:2:3"),
+        ] {
+            assert_eq!(Cursor::decode(&token), None, "{token}");
+        }
+        assert!(params("cursor=bm90IGEgY3Vyc29y").is_err());
+    }
+
+    #[test]
+    fn cursor_of_another_sort_does_not_fit() {
+        let cursor = Cursor {
+            sort: Sort::EndAt,
+            after: After {
+                priority: 0,
+                end_at: NaiveDateTime::default(),
+                id: 1,
+            },
+        };
+        let query = format!("cursor={}", cursor.encode());
+        assert!(!params(&query).unwrap().cursor_fits_sort());
+        assert!(params(&format!("sort=end_at&{query}"))
+            .unwrap()
+            .cursor_fits_sort());
+        assert!(params("").unwrap().cursor_fits_sort());
+    }
+
+    /// a page of `ads` walked like the match list of `GET /ad`, with the ids served
+    async fn page(
+        ads: &[PartialAdvertisement],
+        query: &str,
+        held: &[i32],
+    ) -> (Vec<i32>, Option<String>) {
+        let params = params(query).unwrap();
+        let separation = crate::separation::Separation::from_env();
+        let fetch = |after: Option<After>, limit: usize| {
+            let fetched = ads
+                .iter()
+                .filter(|x| {
+                    after.is_none_or(|after| params.sort.compare(&x.position(), &after).is_gt())
+                })
+                .take(limit)
+                .cloned()
+                .collect();
+            std::future::ready(Ok((fetched, false)))
+        };
+        let admit = |x: PartialAdvertisement| (!held.contains(&x.id)).then_some(x);
+        let page = walk(&params, separation.separator(), fetch, admit)
+            .await
+            .unwrap();
+        let ids = page.items.iter().map(|x| x.id).collect();
+        (ids, page.next_cursor)
+    }
+
+    #[tokio::test]
+    async fn pages_resume_from_their_cursor() {
+        let ads = matches(&[1, 2, 3, 4, 5, 6, 7]).ads;
+        let (first, cursor) = page(&ads, "limit=3", &[]).await;
+        assert_eq!(first, vec![1, 2, 3]);
+        let cursor = cursor.unwrap();
+
+        let (second, cursor) = page(&ads, &format!("limit=3&cursor={cursor}"), &[]).await;
+        assert_eq!(second, vec![4, 5, 6]);
+        // the last page is short and ends the walk
+        let (last, cursor) = page(&ads, &format!("limit=3&cursor={}", cursor.unwrap()), &[]).await;
+        assert_eq!((last, cursor), (vec![7], None));
+    }
+
+    #[tokio::test]
+    async fn held_back_advertisements_are_neither_repeated_nor_skipped() {
+        let ads = matches(&[1, 2, 3, 4, 5, 6, 7]).ads;
+        let (first, cursor) = page(&ads, "limit=2&offset=1", &[2, 4]).await;
+        // 2 is held back, 1 is skipped by the offset
+        assert_eq!(first, vec![3, 5]);
+        // resumes after 5, the last advertisement considered
+        let query = format!("limit=2&cursor={}", cursor.unwrap());
+        assert_eq!(page(&ads, &query, &[2, 4]).await.0, vec![6, 7]);
+    }
 }
@@ -1,9 +1,31 @@
-use crate::{database::Advertisement as AdvertisementModel, routes::AppState};
+use crate::{
+    database::{Advertisement as AdvertisementModel, BidType, Budget, Insert, Revision, Status},
+    routes::{changes, AppState},
+};
+use axum::extract::{Path, Query};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
 use axum::{extract::State, http::StatusCode, Json};
 use common::{Country, Gender, Platform};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
+/// header naming who made the change, recorded in the revision history
+///
+/// The backend does not authenticate it, as it does not authenticate the admin routes at
+/// all: anyone reaching `/admin` can name any actor. The history is only as trustworthy as
+/// the gateway in front of the admin routes, which must authenticate the caller and
+/// overwrite the header with who it is, dropping any value sent by the client. A request
+/// without the header is recorded as `anonymous`.
+static ACTOR_HEADER: &str = "x-actor";
+
+fn actor(headers: &HeaderMap) -> &str {
+    headers
+        .get(ACTOR_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or("anonymous")
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Advertisement {
@@ -14,6 +36,45 @@ pub struct Advertisement {
     end_at: chrono::NaiveDateTime,
     gender: Option<Gender>,
     platform: Option<Platform>,
+    #[serde(default)]
+    advertiser: Option<String>,
+    #[serde(default)]
+    priority: i32,
+    #[serde(default = "default_weight")]
+    weight: i32,
+    #[serde(default)]
+    bid: i64,
+    #[serde(default)]
+    bid_type: BidType,
+    #[serde(default)]
+    total_budget: Option<i64>,
+    #[serde(default)]
+    daily_budget: Option<i64>,
+    #[serde(default)]
+    impression_cap: Option<i64>,
+    #[serde(default)]
+    daily_impression_cap: Option<i64>,
+    #[serde(default)]
+    category: Option<String>,
+    #[serde(default)]
+    house: bool,
+    /// `http` or `https`, may hold the macros expanded by `GET /track/click`
+    #[serde(default)]
+    landing_url: Option<String>,
+    #[serde(default)]
+    campaign: Option<String>,
+}
+
+impl Advertisement {
+    fn is_valid(&self) -> bool {
+        self.landing_url
+            .as_deref()
+            .is_none_or(|x| x.starts_with("https://") || x.starts_with("http://"))
+    }
+}
+
+fn default_weight() -> i32 {
+    1
 }
 
 impl From<Advertisement> for AdvertisementModel {
@@ -25,18 +86,234 @@ impl From<Advertisement> for AdvertisementModel {
             platform: value.platform,
             gender: value.gender,
             end_at: value.end_at,
+            status: Status::Active,
+            advertiser: value.advertiser,
+            priority: value.priority,
+            weight: value.weight.max(1),
+            bid: value.bid,
+            bid_type: value.bid_type,
+            budget: Budget {
+                total_budget: value.total_budget,
+                daily_budget: value.daily_budget,
+                impression_cap: value.impression_cap,
+                daily_impression_cap: value.daily_impression_cap,
+            },
+            category: value.category,
+            house: value.house,
+            landing_url: value.landing_url,
+            campaign: value.campaign,
         }
     }
 }
 
-#[tracing::instrument(name = "POST /ad", skip(state))]
+#[derive(Serialize)]
+pub struct Created {
+    id: i32,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    merged: bool,
+}
+
+/// what to do when an identical advertisement already exists
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum OnDuplicate {
+    /// respond 409 with the id of the existing advertisement
+    #[default]
+    Reject,
+    /// respond as if created, with the id of the existing advertisement
+    Merge,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CreateParams {
+    #[serde(default)]
+    on_duplicate: OnDuplicate,
+}
+
+#[tracing::instrument(name = "POST /ad", skip(state, headers))]
 pub async fn handler(
     State(state): State<Arc<AppState>>,
+    Query(query): Query<CreateParams>,
+    headers: HeaderMap,
+    Json(params): Json<Advertisement>,
+) -> Result<Response, StatusCode> {
+    if !params.is_valid() {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    match state.client.insert(&params.into(), actor(&headers)).await {
+        Ok(Insert::Inserted(id)) => {
+            // visible on this backend right away, others follow through `changes::listen`
+            changes::apply(&state, &[id]).await;
+            Ok(Json(Created { id, merged: false }).into_response())
+        }
+        Ok(Insert::Duplicate(id)) => Ok(match query.on_duplicate {
+            OnDuplicate::Reject => {
+                (StatusCode::CONFLICT, Json(Created { id, merged: false })).into_response()
+            }
+            OnDuplicate::Merge => Json(Created { id, merged: true }).into_response(),
+        }),
+        Err(err) => {
+            tracing::error!("failed to insert advertisement: {:?}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[tracing::instrument(name = "PUT /admin/ads/{id}", skip(state, headers))]
+pub async fn update(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i32>,
+    headers: HeaderMap,
     Json(params): Json<Advertisement>,
 ) -> Result<(), StatusCode> {
-    if let Err(err) = state.client.insert(&params.into()).await {
-        tracing::error!("failed to insert advertisement: {:?}", err);
+    if !params.is_valid() {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
     }
+    match state
+        .client
+        .update(id, params.into(), actor(&headers))
+        .await
+    {
+        Ok(Some(())) => {
+            changes::apply(&state, &[id]).await;
+            Ok(())
+        }
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(err) => {
+            tracing::error!("failed to update advertisement: {:?}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
 
-    Ok(())
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StatusChange {
+    status: Status,
+}
+
+#[tracing::instrument(name = "PUT /admin/ads/{id}/status", skip(state, headers))]
+pub async fn status(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i32>,
+    headers: HeaderMap,
+    Json(params): Json<StatusChange>,
+) -> Result<(), StatusCode> {
+    match state
+        .client
+        .set_status(id, params.status, actor(&headers))
+        .await
+    {
+        Ok(Some(())) => {
+            changes::apply(&state, &[id]).await;
+            Ok(())
+        }
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(err) => {
+            tracing::error!("failed to change advertisement status: {:?}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[tracing::instrument(name = "GET /admin/ads/{id}/history", skip(state))]
+pub async fn history(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i32>,
+) -> Result<Json<Vec<Revision>>, StatusCode> {
+    match state.client.history(id).await {
+        Ok(revisions) if revisions.is_empty() => Err(StatusCode::NOT_FOUND),
+        Ok(revisions) => Ok(Json(revisions)),
+        Err(err) => {
+            tracing::error!("failed to query advertisement history: {:?}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "POST /admin/ads/{id}/history/{revision}/rollback",
+    skip(state, headers)
+)]
+pub async fn rollback(
+    State(state): State<Arc<AppState>>,
+    Path((id, revision)): Path<(i32, i32)>,
+    headers: HeaderMap,
+) -> Result<(), StatusCode> {
+    match state.client.rollback(id, revision, actor(&headers)).await {
+        Ok(Some(())) => {
+            changes::apply(&state, &[id]).await;
+            Ok(())
+        }
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(err) => {
+            tracing::error!("failed to rollback advertisement: {:?}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::ad;
+    use axum::body::to_bytes;
+    use serde_json::{json, Value};
+    use std::env;
+
+    async fn served(state: &Arc<AppState>, query: &str) -> Vec<i64> {
+        let uri = format!("/ad?{query}").parse().unwrap();
+        let params = Query::try_from_uri(&uri).unwrap();
+        let response = ad::handler(State(state.clone()), params).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        body["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["id"].as_i64().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs postgres with the migrations at WRITE_HOST and READ_HOST"]
+    async fn created_advertisement_is_served_right_away() {
+        env::set_var(
+            "PASSWORD",
+            env::var("PASSWORD").unwrap_or("postgres".into()),
+        );
+        env::set_var("TRACKING_SECRET", "test");
+        let state = AppState::shared().await;
+        let age = 60 + rand::random::<u8>() as i32 % 1000;
+        let query = format!("age={age}&limit=100");
+        // the match list of this targeting is cached before the write
+        assert!(served(&state, &query).await.is_empty());
+
+        let advertisement = json!({
+            "title": format!("created {}", rand::random::<u64>()),
+            "from_age": age,
+            "to_age": age + 1,
+            "country": null,
+            "end_at": "2099-01-01T00:00:00",
+            "gender": null,
+            "platform": null,
+        });
+        let response = handler(
+            State(state.clone()),
+            Query(CreateParams {
+                on_duplicate: OnDuplicate::Reject,
+            }),
+            HeaderMap::new(),
+            Json(serde_json::from_value(advertisement).unwrap()),
+        )
+        .await
+        .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let id = serde_json::from_slice::<Value>(&body).unwrap()["id"]
+            .as_i64()
+            .unwrap();
+
+        assert_eq!(served(&state, &query).await, vec![id]);
+    }
 }
@@ -0,0 +1,77 @@
+//! applies advertisement changes notified by postgres to the caches and index of this backend
+use crate::routes::AppState;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// channel notified by the `advertisement_notify` trigger, with `id:version` as payload
+static CHANNEL: &str = "advertisement";
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// `synced` fires after the first resync
+pub async fn listen(state: Arc<AppState>, synced: oneshot::Sender<()>) {
+    let mut synced = Some(synced);
+    loop {
+        match state.client.listen(CHANNEL).await {
+            Ok(mut notifications) => {
+                // changes made while not listening were never delivered
+                resync(&state).await;
+                if let Some(synced) = synced.take() {
+                    let _ = synced.send(());
+                }
+                while let Some(payloads) = notifications.recv_batch().await {
+                    let changes: Vec<(i32, i64)> = payloads
+                        .iter()
+                        .filter_map(|x| {
+                            let (id, version) = x.split_once(':')?;
+                            Some((id.parse().ok()?, version.parse().ok()?))
+                        })
+                        .collect();
+                    let ids: Vec<i32> = changes.iter().map(|(id, _)| *id).collect();
+                    let version = changes.iter().map(|(_, version)| *version).max();
+                    update(&state, &ids, version).await;
+                }
+                tracing::warn!("lost advertisement change listener, reconnecting");
+            }
+            Err(err) => tracing::warn!("failed to listen for advertisement changes: {:?}", err),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// make the current state of the advertisements `ids` visible to `GET /ad` of this backend
+pub async fn apply(state: &AppState, ids: &[i32]) {
+    // the notification of the change may not have arrived yet
+    let version = current_version(state).await;
+    update(state, ids, version).await;
+}
+
+async fn update(state: &AppState, ids: &[i32], version: Option<i64>) {
+    if let Some(index) = &state.index {
+        if let Err(err) = index.reload(&state.client, ids).await {
+            tracing::warn!("failed to reload advertisements {:?}: {:?}", ids, err);
+        }
+    }
+    state.read_cache.invalidate(version);
+}
+
+async fn resync(state: &AppState) {
+    if let Some(index) = &state.index {
+        if let Err(err) = index.resync(&state.client).await {
+            tracing::warn!("failed to resync advertisement index: {:?}", err);
+        }
+    }
+    let version = current_version(state).await;
+    state.read_cache.invalidate(version);
+}
+
+async fn current_version(state: &AppState) -> Option<i64> {
+    match state.client.advertisement_version().await {
+        Ok(version) => Some(version),
+        Err(err) => {
+            tracing::warn!("failed to query advertisement version: {:?}", err);
+            None
+        }
+    }
+}
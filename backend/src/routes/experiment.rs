@@ -0,0 +1,112 @@
+use crate::{
+    database::{Change, Experiment, ExperimentStatus},
+    routes::AppState,
+};
+use axum::extract::Path;
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio_postgres::error::SqlState;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NewExperiment {
+    name: String,
+    variants: Vec<NewVariant>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NewVariant {
+    advertisement_id: i32,
+    /// percentage of users assigned to the variant, the rest sees none of them
+    traffic: i32,
+}
+
+impl NewExperiment {
+    fn is_valid(&self) -> bool {
+        let mut ids = HashSet::new();
+        !self.variants.is_empty()
+            && self
+                .variants
+                .iter()
+                .all(|x| (1..=100).contains(&x.traffic) && ids.insert(x.advertisement_id))
+            && self.variants.iter().map(|x| x.traffic).sum::<i32>() <= 100
+    }
+}
+
+#[derive(Serialize)]
+pub struct Created {
+    id: i32,
+}
+
+#[tracing::instrument(name = "POST /admin/experiments", skip(state))]
+pub async fn create(
+    State(state): State<Arc<AppState>>,
+    Json(params): Json<NewExperiment>,
+) -> Result<Json<Created>, StatusCode> {
+    if !params.is_valid() {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let variants: Vec<_> = params
+        .variants
+        .iter()
+        .map(|x| (x.advertisement_id, x.traffic))
+        .collect();
+    match state
+        .client
+        .create_experiment(&params.name, &variants)
+        .await
+    {
+        Ok(Change::Applied(id)) => Ok(Json(Created { id })),
+        Ok(Change::NotFound) => Err(StatusCode::NOT_FOUND),
+        Ok(Change::Overlap) => Err(StatusCode::CONFLICT),
+        Err(err) if err.code() == Some(&SqlState::FOREIGN_KEY_VIOLATION) => {
+            Err(StatusCode::UNPROCESSABLE_ENTITY)
+        }
+        Err(err) => {
+            tracing::error!("failed to create experiment: {:?}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[tracing::instrument(name = "GET /admin/experiments/{id}", skip(state))]
+pub async fn get(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i32>,
+) -> Result<Json<Experiment>, StatusCode> {
+    match state.client.experiment(id).await {
+        Ok(Some(experiment)) => Ok(Json(experiment)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(err) => {
+            tracing::error!("failed to query experiment: {:?}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StatusChange {
+    status: ExperimentStatus,
+}
+
+/// start or stop an experiment, a restarted experiment keeps its metrics
+#[tracing::instrument(name = "PUT /admin/experiments/{id}/status", skip(state))]
+pub async fn status(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i32>,
+    Json(params): Json<StatusChange>,
+) -> Result<(), StatusCode> {
+    match state.client.set_experiment_status(id, params.status).await {
+        Ok(Change::Applied(_)) => Ok(()),
+        Ok(Change::NotFound) => Err(StatusCode::NOT_FOUND),
+        Ok(Change::Overlap) => Err(StatusCode::CONFLICT),
+        Err(err) => {
+            tracing::error!("failed to change experiment status: {:?}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
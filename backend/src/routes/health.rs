@@ -1,7 +1,18 @@
 use crate::routes::AppState;
 use axum::extract::State;
+use axum::http::StatusCode;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 pub async fn handler(_: State<Arc<AppState>>) -> &'static str {
     "OK"
 }
+
+/// unavailable until the read cache is warm
+pub async fn ready(State(state): State<Arc<AppState>>) -> StatusCode {
+    if state.ready.load(Ordering::Acquire) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
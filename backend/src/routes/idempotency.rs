@@ -0,0 +1,118 @@
+//! replay protection for admin writes via the `Idempotency-Key` header
+use crate::database::Claim;
+use crate::routes::AppState;
+use axum::body::{to_bytes, Body};
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+static IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+static REPLAYED_HEADER: &str = "idempotent-replayed";
+
+const MAX_KEY_LEN: usize = 255;
+const MAX_BODY_LEN: usize = 1 << 20;
+
+pub async fn layer(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return next.run(request).await;
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => key.to_string(),
+        _ => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    let (parts, body) = request.into_parts();
+    let Ok(body) = to_bytes(body, MAX_BODY_LEN).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    let path = parts.uri.path_and_query().map_or("", |x| x.as_str());
+    let request_hash = request_hash(parts.method.as_str(), path, &body);
+
+    let claim = state
+        .client
+        .claim_idempotency_key(
+            &key,
+            &request_hash,
+            state.idempotency_window,
+            state.idempotency_lease,
+        )
+        .await;
+    match claim {
+        Ok(Claim::Acquired) => {}
+        Ok(Claim::Replay { status, response }) => return replay(status, response),
+        Ok(Claim::Mismatch) => return StatusCode::UNPROCESSABLE_ENTITY.into_response(),
+        Ok(Claim::InFlight) => return StatusCode::CONFLICT.into_response(),
+        Err(err) => {
+            tracing::error!("failed to claim idempotency key: {:?}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    let (parts, body) = response.into_parts();
+    let Ok(body) = to_bytes(body, usize::MAX).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    // server errors are not final, a retry with the same key should run again
+    let stored = if parts.status.is_server_error() {
+        state.client.release_idempotency_key(&key).await
+    } else {
+        state
+            .client
+            .complete_idempotency_key(&key, parts.status.as_u16(), &body)
+            .await
+    };
+    if let Err(err) = stored {
+        tracing::error!("failed to store idempotency key: {:?}", err);
+    }
+
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// each part prefixed by its length, so that no two requests hash the same parts
+fn request_hash(method: &str, path: &str, body: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for part in [method.as_bytes(), path.as_bytes(), body] {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().to_vec()
+}
+
+fn replay(status: u16, response: Vec<u8>) -> Response {
+    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::OK);
+    let mut response = if response.is_empty() {
+        status.into_response()
+    } else {
+        (
+            status,
+            [(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            )],
+            response,
+        )
+            .into_response()
+    };
+    response
+        .headers_mut()
+        .insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_hash_separates_parts() {
+        let hash = request_hash("POST", "/ad", b"{}");
+        assert_eq!(hash, request_hash("POST", "/ad", b"{}"));
+        assert_ne!(hash, request_hash("POST", "/ad{", b"}"));
+        assert_ne!(hash, request_hash("POST/", "ad", b"{}"));
+    }
+}
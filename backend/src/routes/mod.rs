@@ -1,23 +1,142 @@
 //! domain routes
-mod ad;
+pub(crate) mod ad;
 mod admin;
+mod changes;
+mod experiment;
 mod health;
+mod idempotency;
+mod report;
+mod track;
+mod traffic;
 
-use crate::database::Client;
+use crate::auction::Rule;
+use crate::database::{Click, Client, Impression};
+use crate::delivery::{self, CounterStore};
+use crate::events::{self, Queue};
+use crate::experiment::Experiments;
+use crate::index::Index;
+use crate::ranking::Placements;
 use crate::routes::ad::ReadCache;
-use axum::{routing, Router};
+use crate::routes::report::Rollup;
+use crate::separation::Separation;
+use crate::shared_cache;
+use crate::tracking::{Kind, Signer};
+use crate::traffic::Detector;
+use crate::warm::Warming;
+use axum::{middleware, routing, Router};
+use moka::future::Cache;
+use std::env;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
 
-struct AppState {
-    pub client: Client,
+pub(crate) struct AppState {
+    pub client: Arc<Client>,
     pub read_cache: ReadCache,
+    /// how long an `Idempotency-Key` is remembered
+    pub idempotency_window: Duration,
+    /// how long a request holding an `Idempotency-Key` may run before a retry takes it over
+    pub idempotency_lease: Duration,
+    pub placements: Placements,
+    /// number of candidates a ranking other than priority draws from
+    pub ranking_pool: usize,
+    pub auction_rule: Rule,
+    /// click-through rate assumed to compare CPC bids with CPM bids
+    pub default_ctr: f64,
+    pub delivery: Arc<dyn CounterStore>,
+    pub experiments: Arc<Experiments>,
+    pub separation: Separation,
+    /// answers cache misses of `GET /ad` in place of postgres, unless disabled
+    pub index: Option<Arc<Index>>,
+    /// how long after a click a conversion is still attributed to it
+    pub conversion_lookback: Duration,
+    /// seals the tracking tokens of served advertisements
+    pub signer: Signer,
+    /// confirmed impressions, appended in batches
+    pub impressions: Queue<Impression>,
+    /// confirmed clicks, appended in batches
+    pub clicks: Queue<Click>,
+    /// flags invalid traffic among tracked events
+    pub traffic: Arc<Detector>,
+    /// how long a click waits for its impression, tracked by any backend, to be appended
+    /// before it is flagged as having none
+    pub impression_grace: Duration,
+    /// `(kind, request id, advertisement id)` tracked by this backend while its tokens verify,
+    /// so that a token replayed to it is rejected early; events are counted once they are
+    /// appended, whichever backend tracks them
+    ///
+    /// Bounded by `TRACKED_CAPACITY`, 1048576 when unset. A replay of an evicted event is
+    /// queued again, then dropped by its unique key on append without being counted, and a
+    /// click on an evicted impression checks postgres instead.
+    pub tracked: Cache<(Kind, String, i32), ()>,
+    /// set once the read cache is warm, see `GET /ready`
+    pub ready: AtomicBool,
 }
 
 impl AppState {
     async fn new() -> Self {
+        let idempotency_window = env::var("IDEMPOTENCY_WINDOW")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(86400);
+        let idempotency_lease = env::var("IDEMPOTENCY_LEASE")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(30);
+        let ranking_pool = env::var("RANKING_POOL")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(100);
+        let default_ctr = env::var("DEFAULT_CTR")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(0.01);
+        let conversion_lookback = env::var("CONVERSION_LOOKBACK")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(7 * 86400);
+        let tracked_capacity = env::var("TRACKED_CAPACITY")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(1 << 20);
+        let client = Arc::new(Client::new().await);
+        let signer = Signer::from_env();
+        let events = events::Config::from_env();
+        let delivery = delivery::from_env(client.clone());
+        let experiments = Experiments::from_env(client.clone());
         Self {
-            client: Client::new().await,
-            read_cache: ReadCache::new(),
+            index: Index::from_env(client.clone()).await,
+            traffic: Detector::from_env(client.clone()),
+            impression_grace: events.flush_delay(),
+            impressions: Queue::new(
+                client.clone(),
+                &events,
+                track::count_impression(delivery.clone(), experiments.clone()),
+            ),
+            clicks: Queue::new(
+                client.clone(),
+                &events,
+                track::count_click(delivery.clone(), experiments.clone()),
+            ),
+            delivery,
+            experiments,
+            client,
+            read_cache: ReadCache::new(shared_cache::from_env().await),
+            idempotency_window: Duration::from_secs(idempotency_window),
+            idempotency_lease: Duration::from_secs(idempotency_lease),
+            placements: Placements::from_env(),
+            ranking_pool,
+            auction_rule: Rule::from_env(),
+            default_ctr,
+            separation: Separation::from_env(),
+            conversion_lookback: Duration::from_secs(conversion_lookback),
+            tracked: Cache::builder()
+                .max_capacity(tracked_capacity)
+                .time_to_live(signer.ttl())
+                .build(),
+            signer,
+            ready: AtomicBool::new(false),
         }
     }
     async fn shared() -> Arc<Self> {
@@ -26,9 +145,51 @@ impl AppState {
 }
 
 pub async fn get_router() -> Router {
+    let state = AppState::shared().await;
+    let (synced, on_synced) = oneshot::channel();
+    tokio::spawn(changes::listen(state.clone(), synced));
+    tokio::spawn(Warming::from_env().run(state.clone(), on_synced));
+    tokio::spawn(Rollup::from_env().run(state.client.clone()));
+    let idempotent = middleware::from_fn_with_state(state.clone(), idempotency::layer);
+
     Router::new()
         .route("/health", routing::get(health::handler))
+        .route("/ready", routing::get(health::ready))
         .route("/ad", routing::get(ad::handler))
-        .route("/ad", routing::post(admin::handler))
-        .with_state(AppState::shared().await)
+        .route(
+            "/ad",
+            routing::post(admin::handler).layer(idempotent.clone()),
+        )
+        .route("/track/impression", routing::post(track::impression))
+        .route("/track/click", routing::get(track::click))
+        .route("/track/conversion", routing::post(track::conversion))
+        .route(
+            "/admin/ads/:id",
+            routing::put(admin::update).layer(idempotent.clone()),
+        )
+        .route(
+            "/admin/ads/:id/status",
+            routing::put(admin::status).layer(idempotent.clone()),
+        )
+        .route("/admin/ads/:id/history", routing::get(admin::history))
+        .route(
+            "/admin/ads/:id/history/:revision/rollback",
+            routing::post(admin::rollback).layer(idempotent.clone()),
+        )
+        .route(
+            "/admin/experiments",
+            routing::post(experiment::create).layer(idempotent.clone()),
+        )
+        .route("/admin/experiments/:id", routing::get(experiment::get))
+        .route("/admin/reports", routing::get(report::handler))
+        .route("/admin/traffic/bots", routing::get(traffic::bots))
+        .route(
+            "/admin/traffic/bots",
+            routing::put(traffic::set_bots).layer(idempotent.clone()),
+        )
+        .route(
+            "/admin/experiments/:id/status",
+            routing::put(experiment::status).layer(idempotent),
+        )
+        .with_state(state)
 }
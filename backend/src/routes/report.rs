@@ -0,0 +1,279 @@
+//! totals of tracked events for account managers, read from the hourly rollup kept by
+//! every backend
+use crate::database::{Client, Dimension, Filter, ReportRow};
+use crate::routes::AppState;
+use axum::extract::{Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::NaiveDateTime;
+use common::Country;
+use serde::de::IntoDeserializer;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub struct Rollup {
+    interval: Duration,
+}
+
+impl Rollup {
+    /// `REPORT_ROLLUP_INTERVAL` in seconds, 60 when unset
+    pub fn from_env() -> Self {
+        let interval = env::var("REPORT_ROLLUP_INTERVAL")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(60);
+        Self {
+            interval: Duration::from_secs(interval),
+        }
+    }
+    /// recount the latest hours every interval, reports lag behind by up to one interval
+    /// after events are flushed
+    pub async fn run(self, client: Arc<Client>) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            match client.rollup_reports().await {
+                Ok(true) => tracing::info!(counter.report.rollup = 1),
+                Ok(false) => {}
+                Err(err) => tracing::warn!("failed to roll up reports: {:?}", err),
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ReportParams {
+    /// first hour, e.g. `2024-01-01T00:00:00`
+    from: NaiveDateTime,
+    /// end of the range, excluded
+    to: NaiveDateTime,
+    /// comma separated `ad`, `campaign`, `day`, `hour`, `country`, `platform` or `gender`,
+    /// everything in one total when empty
+    #[serde(default)]
+    group_by: String,
+    #[serde(default)]
+    advertisement_id: Option<i32>,
+    #[serde(default)]
+    campaign: Option<String>,
+    #[serde(default)]
+    format: Format,
+}
+
+impl ReportParams {
+    /// the dimensions to group by, unless unknown, repeated, or both `day` and `hour`
+    fn dimensions(&self) -> Option<Vec<Dimension>> {
+        let mut dimensions = Vec::new();
+        for name in self
+            .group_by
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+        {
+            let dimension = Dimension::deserialize(name.into_deserializer())
+                .map_err(|_: serde::de::value::Error| ())
+                .ok()?;
+            if dimensions.contains(&dimension) {
+                return None;
+            }
+            dimensions.push(dimension);
+        }
+        if dimensions.contains(&Dimension::Day) && dimensions.contains(&Dimension::Hour) {
+            return None;
+        }
+        Some(dimensions)
+    }
+}
+
+#[tracing::instrument(name = "GET /admin/reports", skip(state))]
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ReportParams>,
+) -> Result<Response, StatusCode> {
+    let Some(dimensions) = params.dimensions() else {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    };
+    if params.from >= params.to {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let filter = Filter {
+        from: params.from,
+        to: params.to,
+        advertisement_id: params.advertisement_id,
+        campaign: params.campaign,
+    };
+    let rows = match state.client.report(&dimensions, &filter).await {
+        Ok(x) => x,
+        Err(err) => {
+            tracing::error!("failed to query report: {:?}", err);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let records: Vec<_> = rows.iter().map(|x| record(x, &dimensions)).collect();
+    Ok(match params.format {
+        Format::Json => {
+            let objects: Vec<Map<_, _>> = records
+                .into_iter()
+                .map(|x| x.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+                .collect();
+            Json(objects).into_response()
+        }
+        Format::Csv => (
+            [(header::CONTENT_TYPE, "text/csv; charset=utf-8")],
+            csv(&dimensions, &records),
+        )
+            .into_response(),
+    })
+}
+
+static METRICS: [&str; 5] = ["impressions", "clicks", "conversions", "spend", "revenue"];
+
+fn name(dimension: Dimension) -> &'static str {
+    match dimension {
+        Dimension::Ad => "advertisement_id",
+        Dimension::Campaign => "campaign",
+        Dimension::Day => "day",
+        Dimension::Hour => "hour",
+        Dimension::Country => "country",
+        Dimension::Platform => "platform",
+        Dimension::Gender => "gender",
+    }
+}
+
+/// the grouped dimensions of the row, as in a `GET /ad` query, then its metrics
+fn record(row: &ReportRow, dimensions: &[Dimension]) -> Vec<(&'static str, Value)> {
+    let mut record: Vec<_> = dimensions
+        .iter()
+        .map(|&x| {
+            let value = match x {
+                Dimension::Ad => json(&row.advertisement_id),
+                Dimension::Campaign => json(&row.campaign),
+                Dimension::Day => json(&row.day),
+                Dimension::Hour => json(&row.hour),
+                Dimension::Country => json(&row.country.as_ref().map(Country::alpha2)),
+                Dimension::Platform => json(&row.platform),
+                Dimension::Gender => json(&row.gender),
+            };
+            (name(x), value)
+        })
+        .collect();
+    let metrics = [
+        row.impressions,
+        row.clicks,
+        row.conversions,
+        row.spend,
+        row.revenue,
+    ];
+    record.extend(METRICS.into_iter().zip(metrics.map(Value::from)));
+    record
+}
+
+fn json<T: Serialize>(value: &T) -> Value {
+    serde_json::to_value(value).unwrap_or_default()
+}
+
+/// a header line, then one line per record; a missing value is left empty
+fn csv(dimensions: &[Dimension], records: &[Vec<(&'static str, Value)>]) -> String {
+    let header: Vec<_> = dimensions.iter().map(|&x| name(x)).chain(METRICS).collect();
+    let mut csv = header.join(",") + "\n";
+    for record in records {
+        let fields: Vec<_> = record
+            .iter()
+            .map(|(_, value)| match value {
+                Value::Null => String::new(),
+                Value::String(x) => escape(x),
+                x => x.to_string(),
+            })
+            .collect();
+        csv += &fields.join(",");
+        csv.push('\n');
+    }
+    csv
+}
+
+/// quote fields holding a separator, a quote or a line break
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Platform;
+
+    fn params(group_by: &str) -> ReportParams {
+        ReportParams {
+            from: NaiveDateTime::default(),
+            to: NaiveDateTime::default(),
+            group_by: group_by.to_string(),
+            advertisement_id: None,
+            campaign: None,
+            format: Format::Csv,
+        }
+    }
+
+    #[test]
+    fn dimensions_are_parsed_in_order() {
+        assert_eq!(params("").dimensions(), Some(vec![]));
+        assert_eq!(
+            params(" country, ad,day ").dimensions(),
+            Some(vec![Dimension::Country, Dimension::Ad, Dimension::Day])
+        );
+    }
+
+    #[test]
+    fn dimensions_reject_unknown_repeated_and_both_day_and_hour() {
+        assert_eq!(params("ad,week").dimensions(), None);
+        assert_eq!(params("ad,ad").dimensions(), None);
+        assert_eq!(params("day,hour").dimensions(), None);
+    }
+
+    #[test]
+    fn escape_quotes_only_when_needed() {
+        assert_eq!(escape("spring"), "spring");
+        assert_eq!(escape("a,b"), "\"a,b\"");
+        assert_eq!(escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(escape("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn csv_writes_codes_as_in_ad_queries() {
+        let dimensions = [Dimension::Country, Dimension::Platform, Dimension::Campaign];
+        let rows = [
+            ReportRow {
+                country: Country::from_id(410),
+                platform: Some(Platform::Ios),
+                campaign: Some("spring, sale".to_string()),
+                impressions: 10,
+                clicks: 2,
+                spend: 1500,
+                ..Default::default()
+            },
+            ReportRow::default(),
+        ];
+        let records: Vec<_> = rows.iter().map(|x| record(x, &dimensions)).collect();
+        assert_eq!(
+            csv(&dimensions, &records),
+            "country,platform,campaign,impressions,clicks,conversions,spend,revenue\n\
+             KR,ios,\"spring, sale\",10,2,0,1500,0\n\
+             ,,,0,0,0,0,0\n"
+        );
+    }
+}
@@ -0,0 +1,351 @@
+use crate::database::{Attribution, Click, Conversion, Impression, Invalid, Metrics, Postback};
+use crate::delivery::CounterStore;
+use crate::events::{Backpressure, OnAppended};
+use crate::experiment::Experiments;
+use crate::routes::AppState;
+use crate::tracking::{Kind, Rejected};
+use crate::traffic;
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use common::Country;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::instrument;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TrackParams {
+    token: String,
+}
+
+fn rejected(rejected: Rejected) -> StatusCode {
+    match rejected {
+        Rejected::Malformed => StatusCode::BAD_REQUEST,
+        Rejected::Forged => StatusCode::FORBIDDEN,
+        Rejected::Expired => StatusCode::GONE,
+    }
+}
+
+/// remember the event of the served advertisement, false when this backend already tracked it
+async fn first_seen(state: &AppState, kind: Kind, served: &Impression) -> bool {
+    let key = (kind, served.request_id.clone(), served.advertisement_id);
+    state.tracked.entry(key).or_insert(()).await.is_fresh()
+}
+
+async fn forget(state: &AppState, kind: Kind, served: &Impression) {
+    let key = (kind, served.request_id.clone(), served.advertisement_id);
+    state.tracked.invalidate(&key).await;
+}
+
+fn user_agent(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|x| x.to_str().ok())
+}
+
+/// the client address reported by the ingress
+///
+/// `x-real-ip` is set by the ingress, else the right-most `x-forwarded-for` entry is the
+/// hop it appended; entries before it are sent by the client and may be anything.
+fn client_ip(headers: &HeaderMap) -> Option<&str> {
+    let header = |name: &str| headers.get(name).and_then(|x| x.to_str().ok());
+    header("x-real-ip")
+        .or_else(|| header("x-forwarded-for").and_then(|x| x.rsplit(',').next()))
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+}
+
+/// whether the impression of the served advertisement was tracked, assumed when unknown
+async fn impressed(state: &AppState, served: &Impression) -> bool {
+    let key = (
+        Kind::Impression,
+        served.request_id.clone(),
+        served.advertisement_id,
+    );
+    if state.tracked.contains_key(&key) {
+        return true;
+    }
+    match state
+        .client
+        .impressed(&served.request_id, served.advertisement_id)
+        .await
+    {
+        Ok(x) => x,
+        Err(err) => {
+            tracing::warn!("failed to query impression: {:?}", err);
+            true
+        }
+    }
+}
+
+/// record that a served advertisement was shown, once per `impression_token`
+///
+/// Impressions are appended in batches, 503 while the queue is full, and billed once
+/// appended, see [`count_impression`]. Invalid traffic is recorded with its reason, but
+/// not billed.
+#[instrument(name = "POST /track/impression", skip(state, params, headers))]
+pub async fn impression(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<TrackParams>,
+    headers: HeaderMap,
+) -> StatusCode {
+    let mut impression: Impression = match state.signer.verify(Kind::Impression, &params.token) {
+        Ok(x) => x,
+        Err(x) => return rejected(x),
+    };
+    impression.invalid = state.traffic.impression(user_agent(&headers));
+    if !first_seen(&state, Kind::Impression, &impression).await {
+        return StatusCode::CONFLICT;
+    }
+    if state.impressions.push(impression.clone()).await.is_err() {
+        forget(&state, Kind::Impression, &impression).await;
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+    if let Some(reason) = impression.invalid {
+        tracing::info!(counter.traffic.invalid = 1, ?reason, kind = "impression");
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// bill a valid impression and count it for its experiment, once appended
+///
+/// Counting on append rather than on tracking dedupes on the unique key of `impression`,
+/// so that a token replayed to several backends is billed once.
+pub fn count_impression(
+    delivery: Arc<dyn CounterStore>,
+    experiments: Arc<Experiments>,
+) -> OnAppended<Impression> {
+    Box::new(move |impression| {
+        if impression.invalid.is_some() {
+            return;
+        }
+        let id = impression.advertisement_id;
+        if !impression.house {
+            delivery.record(id, 1, impression.cost);
+        }
+        if let Some(experiment_id) = impression.experiment_id {
+            let metrics = Metrics {
+                impressions: 1,
+                ..Default::default()
+            };
+            experiments.record(experiment_id, id, &metrics);
+        }
+    })
+}
+
+/// count a valid click, charging it under a CPC bid, once appended, see [`count_impression`]
+pub fn count_click(
+    delivery: Arc<dyn CounterStore>,
+    experiments: Arc<Experiments>,
+) -> OnAppended<Click> {
+    Box::new(move |click| {
+        let served = &click.served;
+        if served.invalid.is_some() {
+            return;
+        }
+        let id = served.advertisement_id;
+        delivery.click(id, served.click_cost);
+        if let Some(experiment_id) = served.experiment_id {
+            let metrics = Metrics {
+                clicks: 1,
+                ..Default::default()
+            };
+            experiments.record(experiment_id, id, &metrics);
+        }
+    })
+}
+
+/// record a click on a served advertisement, once per `click_token`, and redirect to its
+/// landing URL
+///
+/// The landing URL may hold `{ad_id}`, `{click_id}`, `{country}`, `{platform}` and
+/// `{request_id}`, replaced by those of the click, or by nothing when unknown. The
+/// advertiser reports conversions with the `click_id` to `POST /track/conversion`, once
+/// the click is flushed. A click whose impression is not found yet is recorded once the
+/// impression had time to be appended, see [`AppState::impression_grace`]. Invalid traffic is redirected and recorded with its reason, but
+/// not billed and never credited with a conversion. A valid click is charged under a CPC
+/// bid, its impression was free.
+#[instrument(name = "GET /track/click", skip(state, params, headers))]
+pub async fn click(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<TrackParams>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let served: Impression = state
+        .signer
+        .verify(Kind::Click, &params.token)
+        .map_err(rejected)?;
+    let id = served.advertisement_id;
+    let landing_url = match state.client.landing_url(id).await {
+        Ok(Some(x)) => x,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(err) => {
+            tracing::error!("failed to query landing url: {:?}", err);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let click = Click::new(served);
+    let location = expand(&landing_url, &click.served, click.id);
+    // a repeated click still redirects, it is only counted once
+    if first_seen(&state, Kind::Click, &click.served).await {
+        let invalid = state
+            .traffic
+            .click(&click.served, user_agent(&headers), client_ip(&headers))
+            .await;
+        if invalid.is_none() {
+            // the impression may still be queued by another backend, the redirect does not wait
+            tokio::spawn(async move {
+                let check = || impressed(&state, &click.served);
+                let invalid = traffic::no_impression(state.impression_grace, check).await;
+                record_click(&state, click, invalid).await;
+            });
+        } else {
+            record_click(&state, click, invalid).await;
+        }
+    }
+    Ok((StatusCode::FOUND, [(header::LOCATION, location)]).into_response())
+}
+
+async fn record_click(state: &AppState, mut click: Click, invalid: Option<Invalid>) {
+    click.served.invalid = invalid;
+    if let Err(Backpressure) = state.clicks.push(click.clone()).await {
+        tracing::error!("click queue is full, dropping click {}", click.id);
+        forget(state, Kind::Click, &click.served).await;
+    } else if let Some(reason) = invalid {
+        tracing::info!(counter.traffic.invalid = 1, ?reason, kind = "click");
+    }
+}
+
+fn expand(landing_url: &str, served: &Impression, click_id: i64) -> String {
+    landing_url
+        .replace("{ad_id}", &served.advertisement_id.to_string())
+        .replace("{click_id}", &click_id.to_string())
+        .replace(
+            "{country}",
+            served.country.as_ref().map_or("", Country::alpha2),
+        )
+        .replace("{platform}", &code(&served.platform))
+        .replace("{request_id}", &served.request_id)
+}
+
+/// the value as in a `GET /ad` query, e.g. `US` or `ios`
+fn code<T: Serialize>(value: &Option<T>) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|x| x.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NewConversion {
+    /// `{click_id}` of the landing URL
+    #[serde(default)]
+    click_id: Option<i64>,
+    /// otherwise the `user_id` the advertisement was served to, with its id
+    #[serde(default)]
+    user_id: Option<String>,
+    #[serde(default)]
+    advertisement_id: Option<i32>,
+    /// reported again, the same transaction is not counted twice; without it a click converts once
+    #[serde(default)]
+    transaction_id: Option<String>,
+    /// in micros
+    #[serde(default)]
+    value: i64,
+}
+
+impl NewConversion {
+    fn is_valid(&self) -> bool {
+        (self.click_id.is_some() || (self.user_id.is_some() && self.advertisement_id.is_some()))
+            && self.value >= 0
+    }
+}
+
+#[derive(Serialize)]
+pub struct Converted {
+    id: i64,
+    click_id: i64,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    duplicate: bool,
+}
+
+/// server-to-server conversion postback, attributed to a click within `CONVERSION_LOOKBACK`
+#[instrument(name = "POST /track/conversion", skip(state))]
+pub async fn conversion(
+    State(state): State<Arc<AppState>>,
+    Json(params): Json<NewConversion>,
+) -> Result<Json<Converted>, StatusCode> {
+    if !params.is_valid() {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let postback = Postback {
+        click_id: params.click_id,
+        user_id: params.user_id,
+        advertisement_id: params.advertisement_id,
+        transaction_id: params.transaction_id,
+        value: params.value,
+    };
+    let attribution = state
+        .client
+        .record_conversion(&postback, state.conversion_lookback)
+        .await;
+    match attribution {
+        Ok(Attribution::Converted(conversion)) => {
+            if let Some(experiment_id) = conversion.experiment_id {
+                let metrics = Metrics {
+                    conversions: 1,
+                    revenue: postback.value,
+                    ..Default::default()
+                };
+                let id = conversion.advertisement_id;
+                state.experiments.record(experiment_id, id, &metrics);
+            }
+            Ok(Json(converted(conversion, false)))
+        }
+        Ok(Attribution::Duplicate(conversion)) => Ok(Json(converted(conversion, true))),
+        Ok(Attribution::Unattributed) => Err(StatusCode::NOT_FOUND),
+        Err(err) => {
+            tracing::error!("failed to record conversion: {:?}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn converted(conversion: Conversion, duplicate: bool) -> Converted {
+    Converted {
+        id: conversion.id,
+        click_id: conversion.click_id,
+        duplicate,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    header::HeaderName::from_static(name),
+                    value.parse().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn client_ip_ignores_forwarded_entries_of_the_client() {
+        let forged = headers(&[("x-forwarded-for", "1.2.3.4, 10.0.0.7")]);
+        assert_eq!(client_ip(&forged), Some("10.0.0.7"));
+        let real = headers(&[("x-forwarded-for", "1.2.3.4"), ("x-real-ip", "10.0.0.8")]);
+        assert_eq!(client_ip(&real), Some("10.0.0.8"));
+        assert_eq!(client_ip(&headers(&[("x-forwarded-for", " ")])), None);
+        assert_eq!(client_ip(&HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,37 @@
+use crate::routes::AppState;
+use axum::{extract::State, http::StatusCode, Json};
+use std::sync::Arc;
+
+#[tracing::instrument(name = "GET /admin/traffic/bots", skip(state))]
+pub async fn bots(State(state): State<Arc<AppState>>) -> Result<Json<Vec<String>>, StatusCode> {
+    match state.client.bot_user_agents().await {
+        Ok(patterns) => Ok(Json(patterns)),
+        Err(err) => {
+            tracing::error!("failed to query bot user agents: {:?}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// replace the case-insensitive substrings flagging a user agent as a bot, applied by every
+/// backend within `IVT_SYNC_INTERVAL`
+#[tracing::instrument(name = "PUT /admin/traffic/bots", skip(state))]
+pub async fn set_bots(
+    State(state): State<Arc<AppState>>,
+    Json(patterns): Json<Vec<String>>,
+) -> Result<(), StatusCode> {
+    let patterns: Vec<_> = patterns.iter().map(|x| x.trim().to_lowercase()).collect();
+    if patterns.iter().any(|x| x.is_empty() || x.len() > 255) {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    match state.client.set_bot_user_agents(&patterns).await {
+        Ok(()) => {
+            state.traffic.set_bots(&patterns);
+            Ok(())
+        }
+        Err(err) => {
+            tracing::error!("failed to replace bot user agents: {:?}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
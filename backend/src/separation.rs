@@ -0,0 +1,202 @@
+//! rules keeping competing advertisements out of the same response
+use std::collections::{HashMap, HashSet};
+use std::env;
+
+pub trait Separated {
+    fn advertiser(&self) -> Option<&str>;
+    fn category(&self) -> Option<&str>;
+}
+
+pub struct Separation {
+    /// most advertisements of one advertiser in a response, unlimited when `None`
+    per_advertiser: Option<usize>,
+    /// categories never shown together, stored in both orders
+    exclusions: HashSet<(String, String)>,
+}
+
+impl Separation {
+    /// `SEPARATION_PER_ADVERTISER`, 1 when unset and 0 for unlimited, and
+    /// `SEPARATION_EXCLUSIONS` as pairs of categories: `airline:rail,bank:bank`
+    pub fn from_env() -> Self {
+        let per_advertiser = env::var("SEPARATION_PER_ADVERTISER")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(1);
+        let mut exclusions = HashSet::new();
+        if let Ok(raw) = env::var("SEPARATION_EXCLUSIONS") {
+            for entry in raw.split(',').filter(|x| !x.trim().is_empty()) {
+                let Some((a, b)) = entry.split_once(':') else {
+                    tracing::warn!("ignore malformed separation exclusion: {}", entry);
+                    continue;
+                };
+                let (a, b) = (a.trim().to_string(), b.trim().to_string());
+                exclusions.insert((b.clone(), a.clone()));
+                exclusions.insert((a, b));
+            }
+        }
+        Self {
+            per_advertiser: (per_advertiser > 0).then_some(per_advertiser),
+            exclusions,
+        }
+    }
+    /// keep the items, in order, that do not conflict with an item kept before them
+    pub fn apply<T: Separated>(&self, items: Vec<T>) -> Vec<T> {
+        if self.per_advertiser.is_none() && self.exclusions.is_empty() {
+            return items;
+        }
+        let mut separator = self.separator();
+        items.into_iter().filter(|x| separator.admit(x)).collect()
+    }
+    /// the rules applied to items one by one, for a response filled incrementally
+    pub fn separator(&self) -> Separator<'_> {
+        Separator {
+            separation: self,
+            advertisers: HashMap::new(),
+            categories: HashSet::new(),
+        }
+    }
+}
+
+/// what one response holds so far
+pub struct Separator<'a> {
+    separation: &'a Separation,
+    advertisers: HashMap<String, usize>,
+    categories: HashSet<String>,
+}
+
+impl Separator<'_> {
+    /// whether the item fits next to those admitted before it, admitting it if so
+    pub fn admit<T: Separated>(&mut self, item: &T) -> bool {
+        let category = item.category();
+        if let Some(category) = category {
+            let excluded = self.categories.iter().any(|kept| {
+                self.separation
+                    .exclusions
+                    .contains(&(kept.clone(), category.to_string()))
+            });
+            if excluded {
+                return false;
+            }
+        }
+        if let (Some(advertiser), Some(limit)) = (item.advertiser(), self.separation.per_advertiser)
+        {
+            let count = self.advertisers.entry(advertiser.to_string()).or_default();
+            if *count >= limit {
+                return false;
+            }
+            *count += 1;
+        }
+        if let Some(category) = category {
+            self.categories.insert(category.to_string());
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Item {
+        id: i32,
+        advertiser: Option<&'static str>,
+        category: Option<&'static str>,
+    }
+
+    impl Separated for Item {
+        fn advertiser(&self) -> Option<&str> {
+            self.advertiser
+        }
+        fn category(&self) -> Option<&str> {
+            self.category
+        }
+    }
+
+    fn item(id: i32, advertiser: Option<&'static str>, category: Option<&'static str>) -> Item {
+        Item {
+            id,
+            advertiser,
+            category,
+        }
+    }
+
+    fn separation(per_advertiser: Option<usize>, exclusions: &[(&str, &str)]) -> Separation {
+        Separation {
+            per_advertiser,
+            exclusions: exclusions
+                .iter()
+                .flat_map(|(a, b)| {
+                    [
+                        (a.to_string(), b.to_string()),
+                        (b.to_string(), a.to_string()),
+                    ]
+                })
+                .collect(),
+        }
+    }
+
+    fn ids(items: Vec<Item>) -> Vec<i32> {
+        items.into_iter().map(|x| x.id).collect()
+    }
+
+    #[test]
+    fn limits_items_per_advertiser() {
+        let items = vec![
+            item(1, Some("acme"), None),
+            item(2, Some("acme"), None),
+            item(3, Some("globex"), None),
+            item(4, Some("acme"), None),
+            item(5, None, None),
+            item(6, None, None),
+        ];
+        assert_eq!(
+            ids(separation(Some(2), &[]).apply(items)),
+            vec![1, 2, 3, 5, 6]
+        );
+    }
+
+    #[test]
+    fn excludes_categories_in_both_orders() {
+        let separation = separation(None, &[("airline", "rail")]);
+        let items = vec![
+            item(1, None, Some("rail")),
+            item(2, None, Some("airline")),
+            item(3, None, Some("rail")),
+            item(4, None, Some("bank")),
+        ];
+        assert_eq!(ids(separation.apply(items)), vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn excludes_a_category_from_itself() {
+        let separation = separation(None, &[("bank", "bank")]);
+        let items = vec![
+            item(1, None, Some("bank")),
+            item(2, None, Some("bank")),
+            item(3, None, None),
+        ];
+        assert_eq!(ids(separation.apply(items)), vec![1, 3]);
+    }
+
+    #[test]
+    fn dropped_item_does_not_count() {
+        // the second acme item is dropped for its category, so the third one still fits
+        let separation = separation(Some(2), &[("airline", "rail")]);
+        let items = vec![
+            item(1, Some("acme"), Some("airline")),
+            item(2, Some("acme"), Some("rail")),
+            item(3, Some("acme"), None),
+            item(4, Some("acme"), None),
+        ];
+        assert_eq!(ids(separation.apply(items)), vec![1, 3]);
+    }
+
+    #[test]
+    fn unlimited_keeps_everything() {
+        let items = vec![
+            item(1, Some("acme"), Some("bank")),
+            item(2, Some("acme"), Some("bank")),
+        ];
+        assert_eq!(ids(separation(None, &[]).apply(items)), vec![1, 2]);
+    }
+}
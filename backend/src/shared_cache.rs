@@ -0,0 +1,212 @@
+//! cache tier shared by every replica, behind the in-process read cache of `GET /ad`
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// serialized values shared across replicas
+///
+/// A cache is an optimization, so implementations log their failures and answer as
+/// if the key was missing rather than failing the request.
+///
+/// The in-process tier of [`crate::routes::ad::ReadCache`] is deliberately not an
+/// implementation: it holds deserialized lists keyed by generation, with their refresh
+/// state and stale fallback, and sits on every request, so a byte interface would
+/// serialize each hit and lose what makes it serve stale.
+pub trait SharedCache: Send + Sync {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<Vec<u8>>>;
+    fn set<'a>(&'a self, key: &'a str, value: Vec<u8>, ttl: Duration) -> BoxFuture<'a, ()>;
+    /// set `key` for `ttl` unless it exists, whether it was set; true when the cache fails,
+    /// so that a caller never waits on an unavailable cache
+    fn add<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, bool>;
+    fn remove<'a>(&'a self, key: &'a str) -> BoxFuture<'a, ()>;
+}
+
+/// `READ_CACHE_REDIS_URL` of a server speaking the redis protocol, no shared tier when unset
+///
+/// Commands time out after `READ_CACHE_REDIS_TIMEOUT` milliseconds, 100 when unset.
+pub async fn from_env() -> Option<Arc<dyn SharedCache>> {
+    let url = env::var("READ_CACHE_REDIS_URL").ok()?;
+    let timeout = env::var("READ_CACHE_REDIS_TIMEOUT")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(100);
+    match Redis::connect(&url, Duration::from_millis(timeout)).await {
+        Ok(redis) => Some(Arc::new(redis)),
+        Err(err) => {
+            tracing::warn!(
+                "failed to connect the shared cache, running without: {:?}",
+                err
+            );
+            None
+        }
+    }
+}
+
+/// reconnects in the background after losing its connection
+pub struct Redis(ConnectionManager);
+
+impl Redis {
+    pub async fn connect(url: &str, timeout: Duration) -> redis::RedisResult<Self> {
+        let config = ConnectionManagerConfig::new()
+            .set_connection_timeout(timeout)
+            .set_response_timeout(timeout);
+        let client = redis::Client::open(url)?;
+        Ok(Self(
+            ConnectionManager::new_with_config(client, config).await?,
+        ))
+    }
+    async fn query<T: redis::FromRedisValue>(&self, cmd: &redis::Cmd) -> Option<T> {
+        match cmd.query_async(&mut self.0.clone()).await {
+            Ok(x) => Some(x),
+            Err(err) => {
+                tracing::warn!("shared cache command failed: {:?}", err);
+                None
+            }
+        }
+    }
+}
+
+impl SharedCache for Redis {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<Vec<u8>>> {
+        Box::pin(async move { self.query(redis::cmd("GET").arg(key)).await.flatten() })
+    }
+    fn set<'a>(&'a self, key: &'a str, value: Vec<u8>, ttl: Duration) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let cmd = redis::cmd("SET")
+                .arg(key)
+                .arg(value)
+                .arg("PX")
+                .arg(ttl.as_millis() as u64)
+                .to_owned();
+            self.query::<()>(&cmd).await;
+        })
+    }
+    fn add<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, bool> {
+        Box::pin(async move {
+            let cmd = redis::cmd("SET")
+                .arg(key)
+                .arg(1)
+                .arg("NX")
+                .arg("PX")
+                .arg(ttl.as_millis() as u64)
+                .to_owned();
+            // nil when the key exists
+            self.query::<Option<String>>(&cmd)
+                .await
+                .is_none_or(|x| x.is_some())
+        })
+    }
+    fn remove<'a>(&'a self, key: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            self.query::<()>(redis::cmd("DEL").arg(key)).await;
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    /// values of one process, expiring like those of a server
+    #[derive(Default)]
+    pub struct Memory(Mutex<HashMap<String, (Vec<u8>, Instant)>>);
+
+    impl Memory {
+        fn live(&self) -> std::sync::MutexGuard<'_, HashMap<String, (Vec<u8>, Instant)>> {
+            let mut values = self.0.lock().unwrap();
+            values.retain(|_, (_, expires_at)| *expires_at > Instant::now());
+            values
+        }
+    }
+
+    impl SharedCache for Memory {
+        fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<Vec<u8>>> {
+            Box::pin(async move { self.live().get(key).map(|(x, _)| x.clone()) })
+        }
+        fn set<'a>(&'a self, key: &'a str, value: Vec<u8>, ttl: Duration) -> BoxFuture<'a, ()> {
+            Box::pin(async move {
+                self.live()
+                    .insert(key.to_string(), (value, Instant::now() + ttl));
+            })
+        }
+        fn add<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, bool> {
+            Box::pin(async move {
+                let mut values = self.live();
+                if values.contains_key(key) {
+                    return false;
+                }
+                values.insert(key.to_string(), (vec![b'1'], Instant::now() + ttl));
+                true
+            })
+        }
+        fn remove<'a>(&'a self, key: &'a str) -> BoxFuture<'a, ()> {
+            Box::pin(async move {
+                self.live().remove(key);
+            })
+        }
+    }
+
+    /// keys of one run, so that runs against the same server do not collide
+    fn key(name: &str) -> String {
+        format!("test:{:016x}:{name}", rand::random::<u64>())
+    }
+
+    async fn round_trip(cache: &dyn SharedCache) {
+        let key = key("value");
+        assert_eq!(cache.get(&key).await, None);
+        cache
+            .set(&key, b"list".to_vec(), Duration::from_secs(10))
+            .await;
+        assert_eq!(cache.get(&key).await, Some(b"list".to_vec()));
+        cache.remove(&key).await;
+        assert_eq!(cache.get(&key).await, None);
+    }
+
+    async fn expiry(cache: &dyn SharedCache) {
+        let key = key("expiring");
+        cache
+            .set(&key, b"list".to_vec(), Duration::from_millis(50))
+            .await;
+        assert!(cache.get(&key).await.is_some());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(cache.get(&key).await, None);
+    }
+
+    async fn claim(cache: &dyn SharedCache) {
+        let key = key("loading");
+        assert!(cache.add(&key, Duration::from_secs(10)).await);
+        assert!(!cache.add(&key, Duration::from_secs(10)).await);
+        cache.remove(&key).await;
+        assert!(cache.add(&key, Duration::from_millis(50)).await);
+        // a claim of a replica that died is released by its TTL
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(cache.add(&key, Duration::from_secs(10)).await);
+        cache.remove(&key).await;
+    }
+
+    #[tokio::test]
+    async fn memory() {
+        let cache = Memory::default();
+        round_trip(&cache).await;
+        expiry(&cache).await;
+        claim(&cache).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a server speaking the redis protocol at REDIS_URL"]
+    async fn redis() {
+        let url = env::var("REDIS_URL").expect("REDIS_URL is set");
+        let cache = Redis::connect(&url, Duration::from_secs(1)).await.unwrap();
+        round_trip(&cache).await;
+        expiry(&cache).await;
+        claim(&cache).await;
+    }
+}
@@ -0,0 +1,202 @@
+//! sealed, expiring tokens tying tracked events to the advertisements actually served
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::time::Duration;
+
+/// length of the random nonce leading every token
+const NONCE_LEN: usize = 24;
+
+/// event a token is valid for, a token of one kind is rejected for another
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Impression,
+    Click,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    kind: Kind,
+    /// unix seconds
+    expires_at: i64,
+    claims: T,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Rejected {
+    Malformed,
+    /// not sealed with the key of this deployment, or sealed for another kind
+    Forged,
+    Expired,
+}
+
+/// seals claims into tokens that clients can pass back but neither read nor alter, so that
+/// claims such as the price of an impression stay on the server
+pub struct Signer {
+    cipher: XChaCha20Poly1305,
+    ttl: Duration,
+}
+
+impl Signer {
+    /// `TRACKING_SECRET` shared by every replica, and `TRACKING_TOKEN_TTL` in seconds, 3600 when unset
+    ///
+    /// Panics without a secret: a key of its own per replica would reject the tokens sealed
+    /// by every other replica as forged.
+    pub fn from_env() -> Self {
+        let secret = env::var("TRACKING_SECRET").expect("TRACKING_SECRET must be set");
+        let ttl = env::var("TRACKING_TOKEN_TTL")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(3600);
+        Self::new(secret.as_bytes(), Duration::from_secs(ttl))
+    }
+    /// a secret of any length, stretched to the key size
+    pub fn new(secret: &[u8], ttl: Duration) -> Self {
+        let key = Sha256::digest(secret);
+        Self {
+            cipher: XChaCha20Poly1305::new(&key),
+            ttl,
+        }
+    }
+    /// how long a sealed token verifies
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+    /// the nonce then the encrypted envelope, base64url encoded
+    pub fn sign<T: Serialize>(&self, kind: Kind, claims: &T) -> String {
+        self.seal(&Envelope {
+            kind,
+            expires_at: Utc::now().timestamp() + self.ttl.as_secs() as i64,
+            claims,
+        })
+    }
+    fn seal<T: Serialize>(&self, envelope: &Envelope<T>) -> String {
+        let payload = serde_json::to_vec(envelope).expect("claims are serializable");
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .cipher
+            .encrypt(&nonce, payload.as_slice())
+            .expect("payload fits the cipher");
+        let mut token = nonce.to_vec();
+        token.extend(sealed);
+        BASE64_URL_SAFE_NO_PAD.encode(token)
+    }
+    pub fn verify<T: DeserializeOwned>(&self, kind: Kind, token: &str) -> Result<T, Rejected> {
+        let token = BASE64_URL_SAFE_NO_PAD
+            .decode(token)
+            .map_err(|_| Rejected::Malformed)?;
+        if token.len() < NONCE_LEN {
+            return Err(Rejected::Malformed);
+        }
+        let (nonce, sealed) = token.split_at(NONCE_LEN);
+        let payload = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), sealed)
+            .map_err(|_| Rejected::Forged)?;
+
+        let envelope: Envelope<T> =
+            serde_json::from_slice(&payload).map_err(|_| Rejected::Malformed)?;
+        if envelope.kind != kind {
+            return Err(Rejected::Forged);
+        }
+        if envelope.expires_at < Utc::now().timestamp() {
+            return Err(Rejected::Expired);
+        }
+        Ok(envelope.claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Claims {
+        advertisement_id: i32,
+        cost: i64,
+    }
+
+    const CLAIMS: Claims = Claims {
+        advertisement_id: 7,
+        cost: 1234567,
+    };
+
+    fn signer() -> Signer {
+        Signer::new(b"secret", Duration::from_secs(60))
+    }
+
+    #[test]
+    fn verifies_own_tokens() {
+        let token = signer().sign(Kind::Impression, &CLAIMS);
+        assert_eq!(signer().verify(Kind::Impression, &token), Ok(CLAIMS));
+    }
+
+    #[test]
+    fn claims_are_unreadable() {
+        let token = signer().sign(Kind::Impression, &CLAIMS);
+        let raw = BASE64_URL_SAFE_NO_PAD.decode(&token).unwrap();
+        let raw = String::from_utf8_lossy(&raw);
+        assert!(!raw.contains("cost") && !raw.contains("1234567"));
+        // a random nonce per token, equal claims do not give equal tokens
+        assert_ne!(token, signer().sign(Kind::Impression, &CLAIMS));
+    }
+
+    #[test]
+    fn rejects_other_kind() {
+        let token = signer().sign(Kind::Impression, &CLAIMS);
+        assert_eq!(
+            signer().verify::<Claims>(Kind::Click, &token),
+            Err(Rejected::Forged)
+        );
+    }
+
+    #[test]
+    fn rejects_expired() {
+        let signer = signer();
+        let token = signer.seal(&Envelope {
+            kind: Kind::Click,
+            expires_at: Utc::now().timestamp() - 1,
+            claims: CLAIMS,
+        });
+        assert_eq!(
+            signer.verify::<Claims>(Kind::Click, &token),
+            Err(Rejected::Expired)
+        );
+    }
+
+    #[test]
+    fn rejects_forged() {
+        let token = signer().sign(Kind::Impression, &CLAIMS);
+        let other = Signer::new(b"other secret", Duration::from_secs(60));
+        assert_eq!(
+            other.verify::<Claims>(Kind::Impression, &token),
+            Err(Rejected::Forged)
+        );
+
+        let mut raw = BASE64_URL_SAFE_NO_PAD.decode(&token).unwrap();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        let tampered = BASE64_URL_SAFE_NO_PAD.encode(raw);
+        assert_eq!(
+            signer().verify::<Claims>(Kind::Impression, &tampered),
+            Err(Rejected::Forged)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        for token in ["", "not base64!", "c2hvcnQ"] {
+            assert_eq!(
+                signer().verify::<Claims>(Kind::Impression, token),
+                Err(Rejected::Malformed),
+                "{token}"
+            );
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! invalid traffic among tracked impressions and clicks, stored with a reason but neither
+//! billed nor reported
+use crate::database::{Client, Impression, Invalid};
+use chrono::Utc;
+use moka::future::Cache;
+use std::env;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+
+pub struct Detector {
+    /// lowercase substrings of bot user agents, reloaded every sync interval
+    bots: RwLock<Vec<String>>,
+    /// clicks counted by `ip:` address or `user:` id, each for a window from its first click
+    clicks: Cache<String, Arc<AtomicU64>>,
+    /// most clicks of an IP address or user within a window
+    click_limit: u64,
+    /// least time between serving and a click of a person
+    min_click_delay: Duration,
+}
+
+impl Detector {
+    /// `IVT_CLICK_LIMIT` clicks per IP address or user within `IVT_CLICK_WINDOW` seconds, 20
+    /// and 60 when unset, `IVT_MIN_CLICK_DELAY` in milliseconds, 500 when unset, and
+    /// `IVT_SYNC_INTERVAL` in seconds between reloads of the bot list, 60 when unset
+    ///
+    /// Click rates are counted by each backend alone, for at most `IVT_CLICK_SUBJECTS`
+    /// addresses and users, 65536 when unset, beyond which some are forgotten early.
+    pub fn from_env(client: Arc<Client>) -> Arc<Self> {
+        let var = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(default)
+        };
+        let detector = Arc::new(Self {
+            bots: RwLock::new(Vec::new()),
+            clicks: Cache::builder()
+                .max_capacity(var("IVT_CLICK_SUBJECTS", 65536))
+                .time_to_live(Duration::from_secs(var("IVT_CLICK_WINDOW", 60)))
+                .build(),
+            click_limit: var("IVT_CLICK_LIMIT", 20),
+            min_click_delay: Duration::from_millis(var("IVT_MIN_CLICK_DELAY", 500)),
+        });
+        let interval = Duration::from_secs(var("IVT_SYNC_INTERVAL", 60));
+        let weak = Arc::downgrade(&detector);
+        tokio::spawn(async move { Self::sync_loop(weak, client, interval).await });
+        detector
+    }
+    async fn sync_loop(detector: Weak<Self>, client: Arc<Client>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let Some(detector) = detector.upgrade() else {
+                return;
+            };
+            match client.bot_user_agents().await {
+                Ok(patterns) => detector.set_bots(&patterns),
+                Err(err) => tracing::warn!("failed to load bot user agents: {:?}", err),
+            }
+        }
+    }
+    pub fn set_bots(&self, patterns: &[String]) {
+        *self.bots.write().unwrap() = patterns.iter().map(|x| x.to_lowercase()).collect();
+    }
+    fn is_bot(&self, user_agent: Option<&str>) -> bool {
+        let Some(user_agent) = user_agent else {
+            return false;
+        };
+        let user_agent = user_agent.to_lowercase();
+        self.bots
+            .read()
+            .unwrap()
+            .iter()
+            .any(|x| user_agent.contains(x.as_str()))
+    }
+    pub fn impression(&self, user_agent: Option<&str>) -> Option<Invalid> {
+        self.is_bot(user_agent).then_some(Invalid::BotUserAgent)
+    }
+    /// reason to flag the click, short of checking its impression
+    pub async fn click(
+        &self,
+        served: &Impression,
+        user_agent: Option<&str>,
+        ip: Option<&str>,
+    ) -> Option<Invalid> {
+        if self.is_bot(user_agent) {
+            return Some(Invalid::BotUserAgent);
+        }
+        let mut exceeded = false;
+        let subjects = [
+            ip.map(|x| format!("ip:{x}")),
+            served.user_id.as_ref().map(|x| format!("user:{x}")),
+        ];
+        for subject in subjects.into_iter().flatten() {
+            let count = self
+                .clicks
+                .get_with(subject, async { Arc::new(AtomicU64::new(0)) })
+                .await;
+            exceeded |= count.fetch_add(1, Ordering::Relaxed) >= self.click_limit;
+        }
+        if exceeded {
+            return Some(Invalid::ClickRate);
+        }
+        let elapsed = (Utc::now().naive_utc() - served.served_at)
+            .to_std()
+            .unwrap_or_default();
+        (elapsed < self.min_click_delay).then_some(Invalid::TooFast)
+    }
+}
+
+/// [`Invalid::NoImpression`] unless `impressed`, asked again after `grace` when the
+/// impression may still wait in the queue of another backend
+pub async fn no_impression<F>(grace: Duration, impressed: impl Fn() -> F) -> Option<Invalid>
+where
+    F: Future<Output = bool>,
+{
+    if impressed().await {
+        return None;
+    }
+    tokio::time::sleep(grace).await;
+    (!impressed().await).then_some(Invalid::NoImpression)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(click_limit: u64, window: Duration) -> Detector {
+        Detector {
+            bots: RwLock::new(Vec::new()),
+            clicks: Cache::builder().time_to_live(window).build(),
+            click_limit,
+            min_click_delay: Duration::from_millis(500),
+        }
+    }
+
+    fn served(user_id: Option<&str>, ago: Duration) -> Impression {
+        Impression {
+            advertisement_id: 1,
+            request_id: "request".to_string(),
+            experiment_id: None,
+            user_id: user_id.map(str::to_string),
+            cost: 0,
+            click_cost: 0,
+            house: false,
+            age: None,
+            country: None,
+            platform: None,
+            gender: None,
+            placement: None,
+            served_at: Utc::now().naive_utc() - chrono::Duration::from_std(ago).unwrap(),
+            invalid: None,
+        }
+    }
+
+    const SLOW: Duration = Duration::from_secs(2);
+
+    #[test]
+    fn bots_match_case_insensitively() {
+        let detector = detector(20, Duration::from_secs(60));
+        detector.set_bots(&["Googlebot".to_string()]);
+        assert_eq!(
+            detector.impression(Some("Mozilla/5.0 (compatible; googlebot/2.1)")),
+            Some(Invalid::BotUserAgent)
+        );
+        assert_eq!(detector.impression(Some("Mozilla/5.0")), None);
+        assert_eq!(detector.impression(None), None);
+    }
+
+    #[tokio::test]
+    async fn clicks_beyond_the_limit_of_an_ip_are_flagged() {
+        let detector = detector(2, Duration::from_secs(60));
+        let served = served(None, SLOW);
+        for _ in 0..2 {
+            assert_eq!(detector.click(&served, None, Some("1.2.3.4")).await, None);
+        }
+        assert_eq!(
+            detector.click(&served, None, Some("1.2.3.4")).await,
+            Some(Invalid::ClickRate)
+        );
+        // other addresses keep their own count
+        assert_eq!(detector.click(&served, None, Some("5.6.7.8")).await, None);
+    }
+
+    #[tokio::test]
+    async fn clicks_of_a_user_count_across_addresses() {
+        let detector = detector(1, Duration::from_secs(60));
+        let served = served(Some("alice"), SLOW);
+        assert_eq!(detector.click(&served, None, Some("1.1.1.1")).await, None);
+        assert_eq!(
+            detector.click(&served, None, Some("2.2.2.2")).await,
+            Some(Invalid::ClickRate)
+        );
+    }
+
+    #[tokio::test]
+    async fn click_limit_resets_after_the_window() {
+        let detector = detector(1, Duration::from_millis(50));
+        let served = served(None, SLOW);
+        assert_eq!(detector.click(&served, None, Some("1.2.3.4")).await, None);
+        assert_eq!(
+            detector.click(&served, None, Some("1.2.3.4")).await,
+            Some(Invalid::ClickRate)
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(detector.click(&served, None, Some("1.2.3.4")).await, None);
+    }
+
+    #[tokio::test]
+    async fn clicks_sooner_than_the_delay_are_flagged() {
+        let detector = detector(20, Duration::from_secs(60));
+        let fast = served(None, Duration::from_millis(100));
+        assert_eq!(
+            detector.click(&fast, None, None).await,
+            Some(Invalid::TooFast)
+        );
+        assert_eq!(detector.click(&served(None, SLOW), None, None).await, None);
+    }
+
+    #[tokio::test]
+    async fn bot_clicks_are_flagged_before_their_rate() {
+        let detector = detector(0, Duration::from_secs(60));
+        detector.set_bots(&["curl".to_string()]);
+        let served = served(None, SLOW);
+        assert_eq!(
+            detector
+                .click(&served, Some("curl/8.0"), Some("1.2.3.4"))
+                .await,
+            Some(Invalid::BotUserAgent)
+        );
+    }
+
+    #[tokio::test]
+    async fn clicks_wait_for_impressions_appended_late() {
+        let grace = Duration::from_millis(20);
+        let asked = AtomicU64::new(0);
+        let late = || async { asked.fetch_add(1, Ordering::Relaxed) > 0 };
+        assert_eq!(no_impression(grace, late).await, None);
+        assert_eq!(asked.load(Ordering::Relaxed), 2);
+        assert_eq!(
+            no_impression(grace, || async { false }).await,
+            Some(Invalid::NoImpression)
+        );
+        assert_eq!(no_impression(grace, || async { true }).await, None);
+    }
+}
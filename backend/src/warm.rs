@@ -0,0 +1,91 @@
+//! persists the most requested read cache keys, and loads them before a new backend is ready
+use crate::routes::ad::{self, Targeting};
+use crate::routes::AppState;
+use std::env;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+pub struct Warming {
+    /// targetings persisted per interval, and loaded at startup
+    keys: usize,
+    /// longest warm-up before reporting ready anyway
+    budget: Duration,
+    interval: Duration,
+}
+
+impl Warming {
+    /// `READ_CACHE_WARM_KEYS`, 500 when unset, `READ_CACHE_WARM_BUDGET` in seconds, 10 when
+    /// unset, and `READ_CACHE_PERSIST_INTERVAL` in seconds, 60 when unset
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            keys: var("READ_CACHE_WARM_KEYS", 500) as usize,
+            budget: Duration::from_secs(var("READ_CACHE_WARM_BUDGET", 10)),
+            interval: Duration::from_secs(var("READ_CACHE_PERSIST_INTERVAL", 60)),
+        }
+    }
+    /// warm the read cache once `synced` fires, then mark the backend ready and keep
+    /// persisting its hottest keys
+    pub async fn run(self, state: Arc<AppState>, synced: oneshot::Receiver<()>) {
+        let warm = async {
+            // a resync started after warming would drop the warmed lists
+            let _ = synced.await;
+            self.warm(&state).await
+        };
+        match tokio::time::timeout(self.budget, warm).await {
+            Ok(count) => tracing::info!("warmed {} read cache lists", count),
+            Err(_) => tracing::warn!("read cache warm-up out of time, reporting ready"),
+        }
+        state.ready.store(true, Ordering::Release);
+
+        let mut interval = tokio::time::interval(self.interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            self.persist(&state).await;
+        }
+    }
+    async fn warm(&self, state: &Arc<AppState>) -> usize {
+        let keys = match state.client.hot_keys(self.keys).await {
+            Ok(x) => x,
+            Err(err) => {
+                tracing::warn!("failed to query read cache keys: {:?}", err);
+                return 0;
+            }
+        };
+        let mut count = 0;
+        for key in keys {
+            // keys of an older targeting format are skipped
+            let Ok(targeting) = serde_json::from_value::<Targeting>(key) else {
+                continue;
+            };
+            match ad::preload(state, targeting).await {
+                Ok(()) => count += 1,
+                Err(err) => tracing::warn!("failed to warm read cache: {:?}", err),
+            }
+        }
+        count
+    }
+    async fn persist(&self, state: &AppState) {
+        let hits: Vec<_> = state
+            .read_cache
+            .hottest(self.keys)
+            .into_iter()
+            .filter_map(|(targeting, count)| Some((serde_json::to_value(targeting).ok()?, count)))
+            .collect();
+        if hits.is_empty() {
+            return;
+        }
+        // only a hint for the next startups, the counts are not kept on failure
+        if let Err(err) = state.client.record_hot_keys(&hits).await {
+            tracing::warn!("failed to persist read cache keys: {:?}", err);
+        }
+    }
+}
@@ -11,6 +11,12 @@ impl Country {
     pub fn into_id(self) -> u32 {
         self.0.numeric_id()
     }
+    pub fn from_id(id: u32) -> Option<Self> {
+        CountryCode::for_id(id).ok().map(Country)
+    }
+    pub fn alpha2(&self) -> &'static str {
+        self.0.alpha2()
+    }
 }
 
 impl Default for Country {
@@ -45,3 +51,26 @@ impl<'a> Deserialize<'a> for Country {
         }
     }
 }
+
+/// `Option<Country>` as its alpha-2 code, for values that are deserialized again
+///
+/// The name a [`Country`] serializes to is not accepted back, so snapshots, tokens and
+/// keys store the code instead, with `#[serde(with = "common::alpha2")]`.
+pub mod alpha2 {
+    use super::Country;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S>(value: &Option<Country>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        value.as_ref().map(Country::alpha2).serialize(serializer)
+    }
+
+    pub fn deserialize<'a, D>(deserializer: D) -> Result<Option<Country>, D::Error>
+    where
+        D: Deserializer<'a>,
+    {
+        Option::<Country>::deserialize(deserializer)
+    }
+}
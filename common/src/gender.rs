@@ -11,3 +11,16 @@ pub enum Gender {
     #[serde(alias = "f")]
     Female,
 }
+
+impl TryFrom<i32> for Gender {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Gender::Unspecified),
+            1 => Ok(Gender::Male),
+            2 => Ok(Gender::Female),
+            x => Err(x),
+        }
+    }
+}
@@ -4,6 +4,6 @@ pub(crate) mod country;
 pub(crate) mod gender;
 pub(crate) mod platform;
 
-pub use country::Country;
+pub use country::{alpha2, Country};
 pub use gender::Gender;
 pub use platform::Platform;
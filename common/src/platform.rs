@@ -14,3 +14,22 @@ pub enum Platform {
     SmartTv = 8,
     Other = 9,
 }
+
+impl TryFrom<i32> for Platform {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            1 => Platform::Android,
+            2 => Platform::Ios,
+            3 => Platform::Web,
+            4 => Platform::Desktop,
+            5 => Platform::Mobile,
+            6 => Platform::Tablet,
+            7 => Platform::Console,
+            8 => Platform::SmartTv,
+            9 => Platform::Other,
+            x => return Err(x),
+        })
+    }
+}
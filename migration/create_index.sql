@@ -1,2 +1,18 @@
 CREATE INDEX idx_advertisement_cond ON advertisement(country, platform, gender) INCLUDE (age_range, end_at);
-CREATE INDEX idx_advertisement_block ON advertisement USING BRIN(age_range, end_at);
\ No newline at end of file
+CREATE INDEX idx_advertisement_block ON advertisement USING BRIN(age_range, end_at);
+CREATE INDEX idx_advertisement_revision_ad ON advertisement_revision(advertisement_id, id);
+CREATE INDEX idx_advertisement_duplicate ON advertisement(title, end_at);
+CREATE INDEX idx_advertisement_priority ON advertisement(priority DESC, id);
+CREATE INDEX idx_advertisement_end_at ON advertisement(end_at, id);
+CREATE INDEX idx_advertisement_house ON advertisement(priority DESC, id) WHERE house;
+CREATE INDEX idx_advertisement_updated_at ON advertisement(updated_at);
+CREATE INDEX idx_experiment_variant_ad ON experiment_variant(advertisement_id);
+CREATE INDEX idx_impression_ad ON impression(advertisement_id, created_at);
+CREATE INDEX idx_click_ad ON click(advertisement_id, created_at);
+CREATE INDEX idx_click_user ON click(user_id, advertisement_id, created_at) WHERE user_id IS NOT NULL;
+CREATE INDEX idx_conversion_ad ON conversion(advertisement_id, created_at);
+CREATE INDEX idx_impression_created_at ON impression USING BRIN(created_at);
+CREATE INDEX idx_click_created_at ON click USING BRIN(created_at);
+CREATE INDEX idx_conversion_created_at ON conversion USING BRIN(created_at);
+CREATE INDEX idx_advertisement_campaign ON advertisement(campaign) WHERE campaign IS NOT NULL;
+CREATE INDEX idx_delivery_total_updated_at ON delivery_total(updated_at);
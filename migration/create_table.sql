@@ -1,10 +1,198 @@
 CREATE TABLE advertisement
 (
-    id        SERIAL PRIMARY KEY,
-    title     VARCHAR(255) NOT NULL,
-    age_range INT4RANGE    NULL,
-    country   int4         NULL,
-    platform  int4         NULL,
-    gender    int4         NULL,
-    end_at    TIMESTAMP    NOT NULL
+    id                   SERIAL PRIMARY KEY,
+    title                VARCHAR(255) NOT NULL,
+    age_range            INT4RANGE    NULL,
+    country              int4         NULL,
+    platform             int4         NULL,
+    gender               int4         NULL,
+    end_at               TIMESTAMP    NOT NULL,
+    status               int4         NOT NULL DEFAULT 1,
+    advertiser           VARCHAR(255) NULL,
+    priority             int4         NOT NULL DEFAULT 0,
+    weight               int4         NOT NULL DEFAULT 1 CHECK (weight > 0),
+    bid                  int8         NOT NULL DEFAULT 0,
+    total_budget         int8         NULL,
+    daily_budget         int8         NULL,
+    impression_cap       int8         NULL,
+    daily_impression_cap int8         NULL,
+    bid_type             int4         NOT NULL DEFAULT 1,
+    category             VARCHAR(255) NULL,
+    house                BOOLEAN      NOT NULL DEFAULT false,
+    landing_url          TEXT         NULL,
+    campaign             VARCHAR(255) NULL,
+    updated_at           TIMESTAMP    NOT NULL DEFAULT now()
 );
+
+CREATE TABLE advertisement_revision
+(
+    id               SERIAL PRIMARY KEY,
+    advertisement_id int4         NOT NULL REFERENCES advertisement (id),
+    actor            VARCHAR(255) NOT NULL,
+    action           int4         NOT NULL,
+    created_at       TIMESTAMP    NOT NULL DEFAULT now(),
+    diff             JSONB        NOT NULL,
+    snapshot         JSONB        NOT NULL
+);
+
+-- advanced on every change, versions the lists shared between backends
+CREATE SEQUENCE advertisement_version;
+
+-- every backend listens, see `backend/src/routes/changes.rs`
+CREATE FUNCTION notify_advertisement() RETURNS trigger AS
+$$
+BEGIN
+    PERFORM pg_notify('advertisement', NEW.id || ':' || nextval('advertisement_version'));
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER advertisement_notify
+    AFTER INSERT OR UPDATE
+    ON advertisement
+    FOR EACH ROW
+EXECUTE FUNCTION notify_advertisement();
+
+CREATE RULE advertisement_revision_no_update AS ON UPDATE TO advertisement_revision DO INSTEAD NOTHING;
+CREATE RULE advertisement_revision_no_delete AS ON DELETE TO advertisement_revision DO INSTEAD NOTHING;
+
+CREATE TABLE idempotency_key
+(
+    key          VARCHAR(255) PRIMARY KEY,
+    request_hash BYTEA        NOT NULL,
+    status       int4         NOT NULL,
+    response     BYTEA        NOT NULL,
+    created_at   TIMESTAMP    NOT NULL DEFAULT now()
+);
+
+CREATE TABLE delivery_counter
+(
+    advertisement_id int4 NOT NULL REFERENCES advertisement (id),
+    day              DATE NOT NULL,
+    impressions      int8 NOT NULL DEFAULT 0,
+    spend            int8 NOT NULL DEFAULT 0,
+    clicks           int8 NOT NULL DEFAULT 0,
+    PRIMARY KEY (advertisement_id, day)
+);
+
+-- lifetime sums of `delivery_counter`, kept in the same transactions
+CREATE TABLE delivery_total
+(
+    advertisement_id int4 PRIMARY KEY REFERENCES advertisement (id),
+    impressions      int8 NOT NULL DEFAULT 0,
+    spend            int8 NOT NULL DEFAULT 0,
+    clicks           int8 NOT NULL DEFAULT 0,
+    updated_at       TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE TABLE experiment
+(
+    id         SERIAL PRIMARY KEY,
+    name       VARCHAR(255) NOT NULL,
+    status     int4         NOT NULL DEFAULT 1,
+    created_at TIMESTAMP    NOT NULL DEFAULT now(),
+    stopped_at TIMESTAMP    NULL
+);
+
+CREATE TABLE experiment_variant
+(
+    experiment_id    int4 NOT NULL REFERENCES experiment (id),
+    advertisement_id int4 NOT NULL REFERENCES advertisement (id),
+    traffic          int4 NOT NULL CHECK (traffic > 0 AND traffic <= 100),
+    impressions      int8 NOT NULL DEFAULT 0,
+    clicks           int8 NOT NULL DEFAULT 0,
+    conversions      int8 NOT NULL DEFAULT 0,
+    revenue          int8 NOT NULL DEFAULT 0,
+    PRIMARY KEY (experiment_id, advertisement_id)
+);
+
+-- most requested targetings of `GET /ad`, loaded by a starting backend, see `backend/src/warm.rs`
+CREATE TABLE read_cache_key
+(
+    targeting JSONB     PRIMARY KEY,
+    score     FLOAT8    NOT NULL,
+    seen_at   TIMESTAMP NOT NULL DEFAULT now()
+);
+
+-- impressions confirmed through their sealed token, billed unless `house`; `cost` is 0 for CPC bids
+CREATE TABLE impression
+(
+    id               BIGSERIAL PRIMARY KEY,
+    advertisement_id int4         NOT NULL REFERENCES advertisement (id),
+    request_id       VARCHAR(32)  NOT NULL,
+    experiment_id    int4         NULL,
+    cost             int8         NOT NULL,
+    house            BOOLEAN      NOT NULL,
+    age              int4         NULL,
+    country          int4         NULL,
+    platform         int4         NULL,
+    gender           int4         NULL,
+    placement        VARCHAR(255) NULL,
+    served_at        TIMESTAMP    NOT NULL,
+    -- reason code of invalid traffic, neither billed nor reported
+    invalid          int4         NULL,
+    created_at       TIMESTAMP    NOT NULL DEFAULT now(),
+    UNIQUE (request_id, advertisement_id)
+);
+
+-- clicks confirmed through their sealed token, once per served advertisement
+CREATE TABLE click
+(
+    -- derived from the served advertisement, see `backend/src/database/click.rs`
+    id               int8         PRIMARY KEY,
+    advertisement_id int4         NOT NULL REFERENCES advertisement (id),
+    request_id       VARCHAR(32)  NOT NULL,
+    experiment_id    int4         NULL,
+    user_id          VARCHAR(255) NULL,
+    age              int4         NULL,
+    country          int4         NULL,
+    platform         int4         NULL,
+    gender           int4         NULL,
+    placement        VARCHAR(255) NULL,
+    served_at        TIMESTAMP    NOT NULL,
+    -- charged for the click of a CPC bid, 0 for CPM bids
+    cost             int8         NOT NULL DEFAULT 0,
+    -- reason code of invalid traffic, neither billed nor reported
+    invalid          int4         NULL,
+    created_at       TIMESTAMP    NOT NULL DEFAULT now(),
+    UNIQUE (request_id, advertisement_id)
+);
+
+-- conversions reported by advertisers, attributed to the click they followed
+CREATE TABLE conversion
+(
+    id               BIGSERIAL PRIMARY KEY,
+    click_id         int8         NOT NULL REFERENCES click (id),
+    advertisement_id int4         NOT NULL REFERENCES advertisement (id),
+    -- empty when not reported, a click then converts once
+    transaction_id   VARCHAR(255) NOT NULL DEFAULT '',
+    value            int8         NOT NULL,
+    created_at       TIMESTAMP    NOT NULL DEFAULT now(),
+    UNIQUE (click_id, transaction_id)
+);
+
+-- hourly totals of tracked events, rolled up by `backend/src/routes/report.rs` for `GET /admin/reports`
+CREATE TABLE report_hour
+(
+    hour             TIMESTAMP NOT NULL,
+    advertisement_id int4      NOT NULL REFERENCES advertisement (id),
+    country          int4      NULL,
+    platform         int4      NULL,
+    gender           int4      NULL,
+    impressions      int8      NOT NULL DEFAULT 0,
+    clicks           int8      NOT NULL DEFAULT 0,
+    conversions      int8      NOT NULL DEFAULT 0,
+    spend            int8      NOT NULL DEFAULT 0,
+    revenue          int8      NOT NULL DEFAULT 0,
+    UNIQUE NULLS NOT DISTINCT (hour, advertisement_id, country, platform, gender)
+);
+
+-- case-insensitive substrings of bot user agents, see `backend/src/traffic.rs`
+CREATE TABLE bot_user_agent
+(
+    pattern VARCHAR(255) PRIMARY KEY
+);
+
+INSERT INTO bot_user_agent (pattern)
+VALUES ('bot'), ('crawler'), ('spider'), ('slurp'), ('headless'), ('phantomjs'), ('curl/'), ('wget/'),
+       ('python-requests'), ('python-urllib'), ('go-http-client'), ('java/'), ('okhttp'), ('scrapy');